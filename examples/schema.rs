@@ -3,7 +3,7 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use cloak::msg::{ConfigResponse, HandleMsg, InitMsg, QueryMsg};
+use cloak::msg::{ConfigResponse, HandleAnswer, HandleMsg, InitMsg, MigrateMsg, QueryMsg};
 //use cloak::state::Config;
 
 fn main() {
@@ -14,7 +14,9 @@ fn main() {
 
     export_schema(&schema_for!(InitMsg), &out_dir);
     export_schema(&schema_for!(HandleMsg), &out_dir);
+    export_schema(&schema_for!(HandleAnswer), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     //export_schema(&schema_for!(Config), &out_dir);
     export_schema(&schema_for!(ConfigResponse), &out_dir);
 }
@@ -0,0 +1,254 @@
+//! This integration test tries to run and call the generated wasm.
+//! It depends on a Wasm build being available, which you can create with `cargo wasm`.
+//! Then running `cargo integration-test` will validate we can properly call into that generated Wasm.
+//! The VM runs in an SGX enclave, so this only works on a host with the Intel SGX SDK.
+//!
+//! You can easily convert unit tests to integration tests.
+//! 1. First copy them over verbatum,
+//! 2. Then change
+//!      let mut deps = mock_dependencies(20, &[]);
+//!    to
+//!      let mut deps = mock_instance(WASM, &[]);
+//! 3. If you access raw storage, where ever you see something like:
+//!      deps.storage.get(CONFIG_KEY).expect("no data stored");
+//!    replace it with:
+//!      deps.with_storage(|store| {
+//!          let data = store.get(CONFIG_KEY).expect("no data stored");
+//!          //...
+//!      });
+//! 4. Anywhere you see query(&deps, ...) you must replace it with query(&mut deps, ...)
+
+use cosmwasm_std::{
+    from_binary, to_binary, Binary, HandleResponse, HumanAddr, InitResponse, StdResult, Uint128,
+};
+use cosmwasm_vm::testing::{
+    handle, init, mock_env, mock_instance_with_gas_limit, query, MockApi, MockQuerier, MockStorage,
+};
+use cosmwasm_vm::Instance;
+
+use cloak::msg::{
+    AdminResponse, ConfigResponse, ContractInfoResponse, Destination, FeesResponse, HandleMsg,
+    HandleReceiveMsg, InitMsg, PendingSeedsResponse, PoolSizeResponse, QueryMsg, SeedStatusResponse,
+    TokenInfo,
+};
+use cloak::rand::sha_256;
+use cloak::state::{FeeSchedule, Payout, TxStatus};
+
+/// Set CLOAK_WASM to run against another build, such as the optimized contract.wasm that
+/// is uploaded
+const DEFAULT_WASM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../target/wasm32-unknown-unknown/release/cloak.wasm");
+
+/// Read when the tests run rather than at compile time, so a missing build fails with
+/// instructions instead of breaking compilation
+fn wasm() -> Vec<u8> {
+    let path = std::env::var("CLOAK_WASM").unwrap_or_else(|_| DEFAULT_WASM.to_string());
+    std::fs::read(&path).unwrap_or_else(|err| {
+        panic!("cannot read the contract at {}: {}. Build it with `cargo wasm` first", path, err)
+    })
+}
+
+/// Ceiling for any single call, so a runaway loop fails the test instead of hanging it
+const GAS_LIMIT: u64 = 100_000_000;
+
+type Deps = Instance<MockStorage, MockApi, MockQuerier>;
+
+fn fee_schedule(flat: u128) -> FeeSchedule {
+    FeeSchedule {
+        flat: Uint128(flat),
+        bps: 0,
+        min: Uint128::zero(),
+        max: None,
+        tiers: vec![],
+        operator_share_bps: 5_000,
+    }
+}
+
+fn init_helper() -> Deps {
+    let mut deps = mock_instance_with_gas_limit(&wasm(), GAS_LIMIT);
+
+    let init_msg = InitMsg {
+        admin: HumanAddr::from("admin"),
+        operators: vec![HumanAddr::from("operator")],
+        tokens: vec![TokenInfo {
+            address: HumanAddr::from("sscrt"),
+            code_hash: "sscrt_hash".to_string(),
+            denom: "uscrt".to_string(),
+            native: false,
+            fee: fee_schedule(100_000),
+            denominations: vec![],
+        }],
+        min_delay: 0,
+        min_pool_size: 0,
+        expiry_blocks: 100,
+        entropy: "init entropy".to_string(),
+    };
+
+    let gas_before = deps.get_gas_left();
+    let res: InitResponse = init(&mut deps, mock_env("admin", &[]), init_msg).unwrap();
+    report_gas("init", gas_before - deps.get_gas_left());
+    // Registers the receive hook with the token
+    assert_eq!(res.messages.len(), 1);
+
+    deps
+}
+
+/// Gas use is only printed, see with `-- --nocapture`. No per call budgets are enforced
+/// until they have been measured on an SGX host
+fn report_gas(call: &str, used: u64) {
+    println!("{} used {} gas", call, used);
+}
+
+fn handle_metered(deps: &mut Deps, sender: &str, msg: HandleMsg) -> StdResult<HandleResponse> {
+    let gas_before = deps.get_gas_left();
+    let res = handle(deps, mock_env(sender, &[]), msg);
+    report_gas("handle", gas_before - deps.get_gas_left());
+    res
+}
+
+fn query_metered(deps: &mut Deps, msg: QueryMsg) -> StdResult<Binary> {
+    let gas_before = deps.get_gas_left();
+    let res = query(deps, msg);
+    report_gas("query", gas_before - deps.get_gas_left());
+    res
+}
+
+fn deposit(deps: &mut Deps, from: &str, commitment: Option<String>) -> HandleResponse {
+    let msg = HandleMsg::Receive {
+        sender: HumanAddr::from(from),
+        from: HumanAddr::from(from),
+        amount: Uint128(1_100_000),
+        msg: Some(to_binary(&HandleReceiveMsg::ReceiveSeed {
+            destinations: vec![Destination { address: HumanAddr::from("wallet"), weight: Uint128(1), commitment }],
+            destination: None,
+            entropy: None,
+            payout: Payout::Native,
+        }).unwrap()),
+        padding: None,
+    };
+    handle_metered(deps, "sscrt", msg).unwrap()
+}
+
+fn tx_code(res: &HandleResponse) -> String {
+    res.log.iter().find(|l| l.key == "tx_code").unwrap().value.trim().to_string()
+}
+
+#[test]
+fn public_queries() {
+    let mut deps = init_helper();
+
+    let config: ConfigResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetConfig {}).unwrap()).unwrap();
+    assert_eq!(config.tokens.len(), 1);
+    assert_eq!(config.tokens[0].fee, fee_schedule(100_000));
+
+    let admin: AdminResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetAdmin {}).unwrap()).unwrap();
+    assert_eq!(admin.admin, HumanAddr::from("admin"));
+
+    let pool: PoolSizeResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+    assert_eq!(pool.pool_size, 0);
+
+    let fees: FeesResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetFees {}).unwrap()).unwrap();
+    assert!(fees.fees.is_empty());
+
+    let info: ContractInfoResponse = from_binary(&query_metered(&mut deps, QueryMsg::ContractInfo {}).unwrap()).unwrap();
+    assert_eq!(info.contract, "cloak");
+}
+
+#[test]
+fn deposit_finalize_and_exit() {
+    let mut deps = init_helper();
+
+    let first = deposit(&mut deps, "alice", None);
+    let second = deposit(&mut deps, "alice", None);
+
+    let pool: PoolSizeResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+    assert_eq!(pool.pool_size, 2);
+    assert_eq!(pool.tvl, Uint128(2_000_000));
+
+    // A redeem followed by the native payout
+    let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_code(&first)], padding: None };
+    assert!(handle_metered(&mut deps, "alice", finalize.clone()).is_err());
+    let res = handle_metered(&mut deps, "operator", finalize).unwrap();
+    assert_eq!(res.messages.len(), 2);
+
+    let exit = HandleMsg::ExitPool { tx_key: tx_code(&second), padding: None };
+    let res = handle_metered(&mut deps, "alice", exit.clone()).unwrap();
+    assert_eq!(res.messages.len(), 1);
+    assert!(handle_metered(&mut deps, "alice", exit).is_err());
+
+    let pool: PoolSizeResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+    assert_eq!(pool.pool_size, 0);
+}
+
+#[test]
+fn change_fee_and_admin() {
+    let mut deps = init_helper();
+
+    let change_fee = HandleMsg::ChangeFee { token: HumanAddr::from("sscrt"), fee: fee_schedule(200_000), padding: None };
+    assert!(handle_metered(&mut deps, "alice", change_fee.clone()).is_err());
+    handle_metered(&mut deps, "admin", change_fee).unwrap();
+
+    let config: ConfigResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetConfig {}).unwrap()).unwrap();
+    assert_eq!(config.tokens[0].fee, fee_schedule(200_000));
+
+    let change_admin = HandleMsg::ChangeAdmin { new_admin: HumanAddr::from("new_admin"), padding: None };
+    handle_metered(&mut deps, "admin", change_admin).unwrap();
+
+    let admin: AdminResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetAdmin {}).unwrap()).unwrap();
+    assert_eq!(admin.admin, HumanAddr::from("admin"));
+    assert_eq!(admin.pending_admin, Some(HumanAddr::from("new_admin")));
+
+    handle_metered(&mut deps, "new_admin", HandleMsg::AcceptAdmin { padding: None }).unwrap();
+
+    let admin: AdminResponse = from_binary(&query_metered(&mut deps, QueryMsg::GetAdmin {}).unwrap()).unwrap();
+    assert_eq!(admin.admin, HumanAddr::from("new_admin"));
+    assert_eq!(admin.pending_admin, None);
+}
+
+#[test]
+fn authenticated_queries() {
+    let mut deps = init_helper();
+
+    let commitment = hex::encode(sha_256(b"alice's secret"));
+    deposit(&mut deps, "alice", Some(commitment.clone()));
+
+    let set_key = HandleMsg::SetViewingKey { key: "alice key".to_string(), padding: None };
+    handle_metered(&mut deps, "alice", set_key).unwrap();
+
+    let pending = QueryMsg::PendingSeeds {
+        address: HumanAddr::from("alice"),
+        key: "alice key".to_string(),
+        page: None,
+        page_size: 10,
+    };
+    let pending: PendingSeedsResponse = from_binary(&query_metered(&mut deps, pending).unwrap()).unwrap();
+    assert_eq!(pending.total, 1);
+    assert_eq!(pending.seeds[0].id, commitment);
+
+    let status = QueryMsg::SeedStatus {
+        address: HumanAddr::from("alice"),
+        key: "alice key".to_string(),
+        id: commitment.clone(),
+    };
+    let status: SeedStatusResponse = from_binary(&query_metered(&mut deps, status).unwrap()).unwrap();
+    assert_eq!(status.status, TxStatus::Pending);
+
+    let wrong_key = QueryMsg::SeedStatus {
+        address: HumanAddr::from("alice"),
+        key: "wrong key".to_string(),
+        id: commitment,
+    };
+    assert!(query_metered(&mut deps, wrong_key).is_err());
+
+    // A permit whose signature doesn't verify is refused
+    let permit_query: QueryMsg = cosmwasm_std::from_slice(br#"{"with_permit":{
+        "permit":{
+            "params":{"permit_name":"test","allowed_tokens":["cosmos2contract"],"chain_id":"secret-2","permissions":["owner"]},
+            "signature":{
+                "pub_key":{"type":"tendermint/PubKeySecp256k1","value":"A5M49l32ZrV+SDsPnoRv8fH7ivNC4gEX9prvd4RwvRaL"},
+                "signature":"hw/Mo3ZZYu1pEiDdymElFkuCuJzg9soDHw+4DxK7cL9rafiyykh7VynS+guotRAKXhfYMwCiyWmiznc6R+UlsQ=="
+            }
+        },
+        "query":{"pending_seeds":{"page_size":10}}
+    }}"#).unwrap();
+    assert!(query_metered(&mut deps, permit_query).is_err());
+}
@@ -1,26 +1,43 @@
 use cosmwasm_std::{
-    log, to_binary, from_binary, Api, Binary, BankMsg, Coin, Env, Extern, HandleResponse, HandleResult, InitResponse, Querier,
-    StdError, StdResult, Storage, Uint128, HumanAddr, CanonicalAddr, CosmosMsg
+    log, to_binary, from_binary, Api, Binary, BankMsg, Coin, Env, Extern, HandleResponse, HandleResult, InitResponse, MigrateResponse, Querier,
+    QueryResult, ReadonlyStorage, StdError, StdResult, Storage, Uint128, HumanAddr, CanonicalAddr, CosmosMsg
 };
 
-use crate::msg::{ConfigResponse, ExistsResponse, PoolSizeResponse, HandleMsg, HandleReceiveMsg, InitMsg, QueryMsg, RedeemHandleMsg};
-use crate::state::{Config, Pair, save, load, may_load, remove, POOL_SIZE_KEY, SNIP20_ADDRESS_KEY, SNIP20_HASH_KEY, CONFIG_KEY, PRNG_SEED_KEY};
+use crate::error::ContractError;
+use crate::msg::{AdminResponse, BucketResponse, ConfigResponse, ContractInfoResponse, DepositOutput, Destination, HandleAnswer, FeeBalanceResponse, FeesResponse, PoolSizeResponse, HandleMsg, HandleReceiveMsg, InitMsg, MigrateMsg, PendingSeed, PendingSeedsResponse, QueryMsg, QueryWithPermit, RedeemHandleMsg, SeedStatusResponse, TokenInfo, TokenPoolResponse, TokenResponse,
+    ResponseStatus::Success};
+use crate::state::{Bucket, Config, ContractStatus, ContractVersion, FeeBalance, FeeSchedule, LegacyConfig, LegacyPair, Pair, Payout, Token, TokenPool, TxRecord, TxStatus, save, load, may_load, remove, add_pending, remove_pending,
+    PairStore, ReadonlyPairStore, ReadonlyCommitmentList, RecordStore, ReadonlyRecordStore, ViewingKeyStore, ReadonlyViewingKeyStore,
+    BUCKETS_KEY, FEES_KEY, POOL_SIZE_KEY, TOKEN_POOLS_KEY, TOKENS_KEY, TVL_KEY, CONFIG_KEY, PRNG_SEED_KEY, PREFIX_REVOKED_PERMITS, STORAGE_VERSION, STORAGE_VERSION_KEY,
+    CONTRACT_VERSION_KEY, LEGACY_SNIP20_ADDRESS_KEY, LEGACY_SNIP20_HASH_KEY};
+use crate::viewing_key::{ViewingKey, VIEWING_KEY_SIZE};
 
 use crate::rand::{sha_256, Prng};
 
-use sha2::{Digest};
-use std::convert::TryInto;
-
 
 //Snip 20 usage
-use secret_toolkit::{snip20::handle::{register_receive_msg,transfer_msg}, 
-utils::{HandleCallback}};
+use secret_toolkit::{snip20::handle::{register_receive_msg,send_msg,transfer_msg},
+utils::{pad_handle_result, pad_query_result, HandleCallback}};
+use secret_toolkit::permit::{validate, Permission, Permit, RevokedPermits};
 
 
-/// pad handle responses and log attributes to blocks of 256 bytes to prevent leaking info based on
-/// response size
+/// pad handle responses, query responses, log attributes and outgoing snip20 messages to blocks of
+/// 256 bytes to prevent leaking info based on response size
 pub const BLOCK_SIZE: usize = 256;
 
+/// Recorded at init and migrate so deployments can be audited
+pub const CONTRACT_NAME: &str = env!("CARGO_PKG_NAME");
+pub const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Denominator of every basis point rate
+pub const MAX_BPS: u16 = 10_000;
+
+/// Most outputs a single deposit can be split into
+pub const MAX_DESTINATIONS: usize = 16;
+
+/// Expiry given to deposits carried over from the first release, about a week of blocks
+pub const LEGACY_EXPIRY_BLOCKS: u64 = 100_800;
+
 
 
 pub fn init<S: Storage, A: Api, Q: Querier>(
@@ -30,59 +47,236 @@ pub fn init<S: Storage, A: Api, Q: Querier>(
 ) -> StdResult<InitResponse> {
     let config = Config {
         admin: deps.api.canonical_address(&msg.admin)?,
-        operator: deps.api.canonical_address(&msg.operator)?,
-        active: true,
-
+        pending_admin: None,
+        operators: canonicalize_operators(&deps.api, &msg.operators)?,
+        status: ContractStatus::Active,
 
-        fee: msg.fee,
-        op_share: msg.op_share,
+        min_delay: msg.min_delay,
+        min_pool_size: msg.min_pool_size,
+        expiry_blocks: validate_expiry(msg.expiry_blocks)?,
 
+        contract_address: env.contract.address.clone(),
     };
 
-    if config.fee <= config.op_share {
-        return Err(StdError::generic_err(
-            "The operator share must be less than the total fee",
-        ));
-    }
-
     let prng_seed: Vec<u8> = sha_256(base64::encode(msg.entropy).as_bytes()).to_vec();
 
     // Initial pool size of 0
-    let pool_init: u16 = 0;
+    let pool_init: u64 = 0;
 
     save(&mut deps.storage, POOL_SIZE_KEY, &pool_init)?;
+    save(&mut deps.storage, TVL_KEY, &Uint128::zero())?;
+    save(&mut deps.storage, TOKEN_POOLS_KEY, &Vec::<TokenPool>::new())?;
     save(&mut deps.storage, PRNG_SEED_KEY, &prng_seed)?;
     save(&mut deps.storage, CONFIG_KEY, &config)?;
-    save(&mut deps.storage, SNIP20_HASH_KEY, &msg.sscrt_hash)?;
-    save(&mut deps.storage, SNIP20_ADDRESS_KEY, &msg.sscrt_addr)?;
+    save(&mut deps.storage, STORAGE_VERSION_KEY, &STORAGE_VERSION)?;
+    save(&mut deps.storage, CONTRACT_VERSION_KEY, &contract_version())?;
+
+
+    let mut tokens: Vec<Token> = vec![];
+    let mut msg_list: Vec<CosmosMsg> = vec![];
+    let mut rng = padding_rng(&deps.storage, &env)?;
+
+    for token_info in msg.tokens {
+        msg_list.extend(register_token(&mut tokens, &env, token_info, random_padding(&mut rng))?);
+    }
+
+    save(&mut deps.storage, TOKENS_KEY, &tokens)?;
+    save(&mut deps.storage, BUCKETS_KEY, &Vec::<Bucket>::new())?;
+    save(&mut deps.storage, FEES_KEY, &Vec::<FeeBalance>::new())?;
 
 
     Ok(InitResponse {
-        messages: vec![
-            register_receive_msg(
-                env.contract_code_hash,
-                None,
-                BLOCK_SIZE,
-                msg.sscrt_hash,
-                msg.sscrt_addr
-            )?
-        ],
+        messages: msg_list,
         log: vec![],
     })
 }
 
+/// Only exported with the `migrate` feature, see Cargo.toml
+pub fn migrate<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    msg: MigrateMsg,
+) -> StdResult<MigrateResponse> {
+    // The first release never stored a version
+    let storage_version: u32 = may_load(&deps.storage, STORAGE_VERSION_KEY)?.unwrap_or(0);
+
+    match storage_version {
+        0 => migrate_from_v0(deps, &env, msg.tx_keys)?,
+        STORAGE_VERSION => {
+            if !msg.tx_keys.is_empty() {
+                return Err(ContractError::UnexpectedKeys.into());
+            }
+        }
+        _ => {
+            return Err(ContractError::UnsupportedStorageVersion { version: storage_version }.into());
+        }
+    }
+
+    save(&mut deps.storage, STORAGE_VERSION_KEY, &STORAGE_VERSION)?;
+    save(&mut deps.storage, CONTRACT_VERSION_KEY, &contract_version())?;
+
+
+    Ok(MigrateResponse {
+        messages: vec![],
+        log: vec![
+            log("previous_storage_version", storage_version),
+            log("storage_version", STORAGE_VERSION),
+            log("version", CONTRACT_VERSION),
+        ],
+        data: None,
+    })
+}
+
+
+
+/// Upgrades the unversioned layout of the first release, which held one snip20, a single
+/// operator, and pairs at the root of storage under their tx_key
+fn migrate_from_v0<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: &Env,
+    tx_keys: Vec<String>,
+) -> StdResult<()> {
+    let legacy_config: LegacyConfig = load(&deps.storage, CONFIG_KEY)?;
+    let snip20_address: HumanAddr = load(&deps.storage, LEGACY_SNIP20_ADDRESS_KEY)?;
+    let snip20_hash: String = load(&deps.storage, LEGACY_SNIP20_HASH_KEY)?;
+
+    // Every pending pair must be listed, since unlisted ones could never be found again.
+    // The first release stored the pool size as a u16
+    let pool_size: u16 = load(&deps.storage, POOL_SIZE_KEY)?;
+    if tx_keys.len() != pool_size as usize {
+        return Err(ContractError::MissingKeys { expected: u64::from(pool_size), got: tx_keys.len() }.into());
+    }
+
+    let config = Config {
+        admin: legacy_config.admin,
+        pending_admin: None,
+        operators: vec![legacy_config.operator],
+        status: if legacy_config.active { ContractStatus::Active } else { ContractStatus::Paused },
+
+        min_delay: 0,
+        min_pool_size: 0,
+        expiry_blocks: LEGACY_EXPIRY_BLOCKS,
+
+        contract_address: env.contract.address.clone(),
+    };
+
+    let token = Token {
+        address: snip20_address,
+        code_hash: snip20_hash,
+        denom: "uscrt".to_string(),
+        native: false,
+        fee: FeeSchedule {
+            flat: legacy_config.fee,
+            bps: 0,
+            min: Uint128::zero(),
+            max: None,
+            tiers: vec![],
+            operator_share_bps: legacy_share_bps(legacy_config.fee, legacy_config.op_share),
+        },
+        denominations: vec![],
+        accepted: true,
+    };
+
+    save(&mut deps.storage, CONFIG_KEY, &config)?;
+    save(&mut deps.storage, TOKENS_KEY, &vec![token.clone()])?;
+    save(&mut deps.storage, BUCKETS_KEY, &Vec::<Bucket>::new())?;
+    save(&mut deps.storage, FEES_KEY, &Vec::<FeeBalance>::new())?;
+    // Recounted below as each pair is carried over
+    save(&mut deps.storage, POOL_SIZE_KEY, &0u64)?;
+    save(&mut deps.storage, TVL_KEY, &Uint128::zero())?;
+    save(&mut deps.storage, TOKEN_POOLS_KEY, &Vec::<TokenPool>::new())?;
+    remove(&mut deps.storage, LEGACY_SNIP20_ADDRESS_KEY);
+    remove(&mut deps.storage, LEGACY_SNIP20_HASH_KEY);
+
+
+    for tx_key in tx_keys {
+        let legacy_pair: LegacyPair = load(&deps.storage, tx_key.as_bytes())?;
+        remove(&mut deps.storage, tx_key.as_bytes());
+
+        // Neither the depositor nor the deposit time were recorded, so the migration
+        // counts as the deposit. Without a depositor these can only leave by key
+        let pair = Pair {
+            gas: legacy_pair.gas,
+            address: legacy_pair.address,
+            token: token.address.clone(),
+            height: env.block.height,
+            time: env.block.time,
+            bucketed: false,
+            depositor: CanonicalAddr(Binary(vec![])),
+            expiry_height: env.block.height.saturating_add(config.expiry_blocks),
+            payout: Payout::Native,
+            committed: false,
+        };
+
+        let commitment = commitment_key(&tx_key);
+        PairStore::from_storage(&mut deps.storage).save(&commitment, &pair)?;
+        add_pending(&mut deps.storage, &pair.depositor, &commitment)?;
+        adjust_pool(&mut deps.storage, &pair, true)?;
+
+        let record = TxRecord {
+            depositor: pair.depositor.clone(),
+            status: TxStatus::Pending,
+            height: pair.height,
+            time: pair.time,
+            closed_height: None,
+            closed_time: None,
+        };
+        RecordStore::from_storage(&mut deps.storage).save(&commitment, &record)?;
+    }
+
+    Ok(())
+}
+
+
+
+/// The first release paid the operator a fixed part of a flat fee. Rounds down
+fn legacy_share_bps(fee: Uint128, op_share: Uint128) -> u16 {
+    if fee.is_zero() {
+        return 0;
+    }
+
+    let share = op_share.u128().saturating_mul(MAX_BPS as u128) / fee.u128();
+    share.min(MAX_BPS as u128) as u16
+}
+
+
+
+fn contract_version() -> ContractVersion {
+    ContractVersion {
+        contract: CONTRACT_NAME.to_string(),
+        version: CONTRACT_VERSION.to_string(),
+    }
+}
+
+
+
 pub fn handle<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     msg: HandleMsg,
 ) -> StdResult<HandleResponse> {
-    match msg {
-        HandleMsg::Receive { sender, from, amount, msg } => receive(deps, env, sender, from, amount, msg),
-        HandleMsg::FinalizeSeed { tx_key} => finalize_seed(deps, env, tx_key),
-        HandleMsg::ExitPool { tx_key } => exit_pool(deps, env, tx_key),
-        HandleMsg::ChangeFee { new_fee, new_op_share } => change_fee(deps, env, new_fee, new_op_share),
-        HandleMsg::ChangeAdmin { new_admin } => change_admin(deps, env, new_admin),
-    }
+    let response = match msg {
+        HandleMsg::Receive { sender, from, amount, msg, .. } => receive(deps, env, sender, from, amount, msg),
+        HandleMsg::DepositNative { destinations, entropy, payout, .. } => deposit_native(deps, env, destinations, entropy, payout),
+        HandleMsg::FinalizeSeed { tx_keys, .. } => finalize_seed(deps, env, tx_keys),
+        HandleMsg::ExitPool { tx_key, .. } => exit_pool(deps, env, tx_key),
+        HandleMsg::ReclaimExpired { depositor, page, page_size, .. } => reclaim_expired(deps, env, depositor, page.unwrap_or(0), page_size),
+        HandleMsg::WithdrawFees { .. } => withdraw_fees(deps, env),
+        HandleMsg::ChangeFee { token, fee, .. } => change_fee(deps, env, token, fee),
+        HandleMsg::ChangeAdmin { new_admin, .. } => change_admin(deps, env, new_admin),
+        HandleMsg::AcceptAdmin { .. } => accept_admin(deps, env),
+        HandleMsg::ChangeOperator { new_operators, .. } => change_operator(deps, env, new_operators),
+        HandleMsg::CreateViewingKey { entropy, .. } => create_viewing_key(deps, env, entropy),
+        HandleMsg::SetViewingKey { key, .. } => set_viewing_key(deps, env, key),
+        HandleMsg::RevokePermit { permit_name, .. } => revoke_permit(deps, env, permit_name),
+        HandleMsg::SetActive { status, .. } => set_active(deps, env, status),
+        HandleMsg::ChangeReleaseConditions { min_delay, min_pool_size, expiry_blocks, .. } => change_release_conditions(deps, env, min_delay, min_pool_size, expiry_blocks),
+        HandleMsg::AddToken { token, .. } => add_token(deps, env, token),
+        HandleMsg::RemoveToken { token, .. } => remove_token(deps, env, token),
+        HandleMsg::SetDenominations { token, denominations, .. } => set_denominations(deps, env, token, denominations),
+    };
+
+    pad_handle_result(response, BLOCK_SIZE)
 }
 
 
@@ -91,45 +285,37 @@ pub fn receive<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     _sender: HumanAddr,
-    _from: HumanAddr,
+    from: HumanAddr,
     amount: Uint128,
     msg: Option<Binary>,
 ) -> HandleResult {
-    let snip20_address: HumanAddr = load(&deps.storage, SNIP20_ADDRESS_KEY)?;
-    let config: Config = load(&deps.storage, CONFIG_KEY)?;
-
-    if env.message.sender != snip20_address {
-        return Err(StdError::generic_err(
-            "Address is not correct snip contract",
-        ));
-    }
-
-    if amount <= config.fee  {
-        return Err(StdError::generic_err(
-            "You have not reached the minumum amount for a transaction",
-        ));
-    }
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
 
-    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
-
-    let gas_amount = (amount - config.fee)?;
+    // Deposits are routed by the snip20 contract that called us
+    let token: Token = match tokens.into_iter().find(|t| !t.native && t.address == env.message.sender) {
+        Some(token) if token.accepted => token,
+        _ => {
+            return Err(ContractError::WrongToken.into());
+        }
+    };
 
     if let Some(bin_msg) = msg {
         match from_binary(&bin_msg)? {
             HandleReceiveMsg::ReceiveSeed {
+                mut destinations,
                 destination,
+                entropy,
+                payout,
             } => {
-                seed_wallet(
-                    deps,
-                    env,
-                    &mut config,
-                    gas_amount, 
-                    destination     
-                )
+                if let Some(address) = destination {
+                    destinations.push(Destination { address, weight: Uint128(1), commitment: None });
+                }
+
+                deposit(deps, env, token, amount, from, destinations, entropy, payout)
             }
         }
      } else {
-        Err(StdError::generic_err("data should be given"))
+        Err(ContractError::MissingMsg.into())
      }
 }
 
@@ -138,130 +324,243 @@ pub fn receive<S: Storage, A: Api, Q: Querier>(
 
 
 
-pub fn seed_wallet<S: Storage, A: Api, Q: Querier>(
+/// For native coins sent along with the message
+pub fn deposit_native<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    config: &mut Config,
-    gas_amount: Uint128,
-    destination: HumanAddr
-) -> StdResult<HandleResponse> {
+    destinations: Vec<Destination>,
+    entropy: Option<String>,
+    payout: Payout,
+) -> HandleResult {
+    if env.message.sent_funds.len() != 1 {
+        return Err(ContractError::WrongFunds.into());
+    }
+    let coin: Coin = env.message.sent_funds[0].clone();
 
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+
+    let token: Token = match tokens.into_iter().find(|t| t.native && t.denom == coin.denom) {
+        Some(token) if token.accepted => token,
+        _ => {
+            return Err(ContractError::WrongCoin { denom: coin.denom }.into());
+        }
+    };
+
+    let from = env.message.sender.clone();
+    deposit(deps, env, token, coin.amount, from, destinations, entropy, payout)
+}
 
-    if !config.active {
-        return Err(StdError::generic_err(
-            "Transfers are currently disabled",
-        ));
-    }
 
 
-    //Generate exit key
-    let prng_seed: Vec<u8> = load(&mut deps.storage, PRNG_SEED_KEY)?;
 
-    //Stored Entropy
-    let new_data: String = format!("{:?}+{}+{}+{}+{}", prng_seed, gas_amount, &env.block.height, &env.block.time, &env.message.sender);
 
-    let hashvalue = sha2::Sha256::digest(new_data.as_bytes());
-    let hash: [u8; 32] = hashvalue.as_slice().try_into().expect("Wrong length");
 
-    //Exported Entropy
-    let export_data: String = format!("{:?}+{}", prng_seed, gas_amount);
+/// Takes the fee and rounds to a denomination, then seeds the rest. Shared by snip20
+/// and native deposits
+#[allow(clippy::too_many_arguments)]
+fn deposit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: Token,
+    amount: Uint128,
+    from: HumanAddr,
+    destinations: Vec<Destination>,
+    entropy: Option<String>,
+    payout: Payout,
+) -> HandleResult {
+    if token.native && payout != Payout::Native {
+        return Err(ContractError::NativePayoutOnly.into());
+    }
+
+    let fee = compute_fee(&token.fee, amount)?;
+    if amount <= fee  {
+        return Err(ContractError::InsufficientAmount.into());
+    }
+
+    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
 
-    let export_hashvalue = sha2::Sha256::digest(export_data.as_bytes());
-    let export_hash: [u8; 32] = export_hashvalue.as_slice().try_into().expect("Wrong length");
+    let gas_amount = (amount - fee)?;
 
-    
-    
+    // Round down to a fixed denomination so the amount can't link deposit and payout
+    let bucketed = !token.denominations.is_empty();
+    let (shares, _) = split_gas(&token, gas_amount, &destinations)?;
 
+    // Only what enters the pool is charged. The fee on the whole deposit bounded the
+    // split, and the refunded remainder never pays more than that
+    let pooled = Uint128(shares.iter().map(|s| s.u128()).sum());
+    let fee = if bucketed {
+        fee.min(compute_fee(&token.fee, pooled)?)
+    } else {
+        fee
+    };
+    let remainder = ((amount - pooled)? - fee)?;
+
+    let contract_address = env.contract.address.clone();
+    let mut rng = padding_rng(&deps.storage, &env)?;
+
+    let outputs = seed_wallet(
+        deps,
+        env,
+        &mut config,
+        &token,
+        destinations.into_iter().zip(shares).collect(),
+        fee,
+        bucketed,
+        &from,
+        entropy,
+        payout
+    )?;
 
 
     let mut msg_list: Vec<CosmosMsg> = vec![];
 
+    // Send back whatever did not fit in the denominations
+    if !remainder.is_zero() {
+        let cosmos_msg = payout_msg(&contract_address, &token, from, remainder, random_padding(&mut rng))?;
+        msg_list.push(cosmos_msg);
+    }
 
-    let snip20_address: HumanAddr = load(&deps.storage, SNIP20_ADDRESS_KEY)?;
-    let callback_code_hash: String = load(&deps.storage, &SNIP20_HASH_KEY)?;
+    // Generated keys are also logged as tx_code, in destination order
+    let logs = outputs
+        .iter()
+        .filter_map(|o| o.tx_key.as_ref().map(|tx_key| log("tx_code", tx_key)))
+        .collect();
 
+    let net_amount = Uint128(outputs.iter().map(|o| o.amount.u128()).sum());
 
-    let padding: Option<String> = None;
+    Ok(HandleResponse {
+        messages: msg_list,
+        log: logs,
+        data: Some(to_binary(&HandleAnswer::Deposit {
+            status: Success,
+            outputs,
+            net_amount,
+            fee,
+            refunded: remainder,
+        })?),
+    })
+}
 
 
-    // Admin Fee
-    let amount: Uint128 = (config.fee - config.op_share)?;
-    let fee_recipient: HumanAddr = deps.api.human_address(&config.admin)?;
-    let cosmos_msg = transfer_msg(
-        fee_recipient,
-        amount,
-        padding.clone(),
-        BLOCK_SIZE,
-        callback_code_hash.clone(),
-        snip20_address.clone(),
-    )?;
-    msg_list.push(cosmos_msg);
 
 
 
-    // Operator fee
-    let redeem_msg = RedeemHandleMsg::Redeem {
-        amount: config.op_share,
-        denom: Some("uscrt".to_string()),
-        padding
-    };
 
-    let cosmos_msg = redeem_msg.to_cosmos_msg(
-        callback_code_hash,
-        snip20_address,
-        None,
-    )?;
-    msg_list.push(cosmos_msg);
+/// Stores one pending tx per output, each under its own key or commitment so they can be
+/// finalized independently
+#[allow(clippy::too_many_arguments)]
+pub fn seed_wallet<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    config: &mut Config,
+    token: &Token,
+    outputs: Vec<(Destination, Uint128)>,
+    fee: Uint128,
+    bucketed: bool,
+    from: &HumanAddr,
+    entropy: Option<String>,
+    payout: Payout
+) -> StdResult<Vec<DepositOutput>> {
+
+
+    if config.status != ContractStatus::Active {
+        return Err(ContractError::DepositsDisabled.into());
+    }
 
 
+    //Generate exit keys
+    let prng_seed: Vec<u8> = load(&deps.storage, PRNG_SEED_KEY)?;
 
-    let withdrawal_coins: Vec<Coin> = vec![Coin {
-        denom: "uscrt".to_string(),
-        amount: config.op_share,
-    }];
+    // The snip20 is the message sender here, so the depositor is mixed in with their entropy
+    let mut user_entropy: Vec<u8> = from.0.as_bytes().to_vec();
+    if let Some(entropy) = entropy {
+        user_entropy.extend_from_slice(entropy.as_bytes());
+    }
 
-    let cosmos_msg = CosmosMsg::Bank(BankMsg::Send {
-        from_address: env.contract.address.clone(),
-        to_address: deps.api.human_address(&config.operator)?,
-        amount: withdrawal_coins,
-    });
-    msg_list.push(cosmos_msg);
+    let mut rng = Prng::new(&prng_seed, &new_entropy(&env, &prng_seed, &user_entropy));
 
+    let tx_keys: Vec<[u8; 32]> = outputs.iter().map(|_| rng.rand_bytes()).collect();
+    // Ratchet the seed with output that is never exported
+    let new_seed: [u8; 32] = rng.rand_bytes();
 
+    save(&mut deps.storage, PRNG_SEED_KEY, &new_seed.to_vec())?;
 
 
 
-    // Store pending tx
-    let new_pair = Pair {
-        gas: gas_amount.u128(),
-        address: destination,
-    };
 
 
-    //save(&mut deps.storage, &export_hash, &new_pair)?;
-    save(&mut deps.storage, PRNG_SEED_KEY, &hash.to_vec())?;
 
-    let tx_key_string = hex::encode(&export_hash);
+    // Fees stay in the contract until withdrawn, so payouts don't mark each deposit
+    let op_share = Uint128(bps_of(fee, token.fee.operator_share_bps)?);
+    let admin_share = (fee - op_share)?;
+    accrue_fees(&mut deps.storage, &token.address, admin_share, op_share)?;
 
 
-    save(&mut deps.storage, tx_key_string.as_bytes(), &new_pair)?;
+    let depositor = deps.api.canonical_address(from)?;
+    let mut deposit_outputs: Vec<DepositOutput> = vec![];
 
+    for ((destination, gas_amount), tx_key) in outputs.into_iter().zip(tx_keys) {
+        // A depositor supplied commitment means only they know the key, so none is published
+        let committed = destination.commitment.is_some();
+        let (commitment, tx_key_string): ([u8; 32], Option<String>) = match destination.commitment {
+            Some(commitment) => (parse_commitment(&commitment)?, None),
+            None => {
+                let tx_key_string = hex::encode(tx_key);
+                (commitment_key(&tx_key_string), Some(tx_key_string))
+            }
+        };
 
+        // Records outlive their pairs, so this also refuses the commitment of a closed tx,
+        // whose history would be overwritten. Catches one commitment given twice as well
+        if ReadonlyRecordStore::from_storage(&deps.storage).may_load(&commitment)?.is_some() {
+            return Err(ContractError::DuplicateCommitment.into());
+        }
 
-    // Adjust pool size
-    let mut pool_size: u16 = load(&deps.storage, POOL_SIZE_KEY)?;
-    pool_size = pool_size + 1;
-    save(&mut deps.storage, POOL_SIZE_KEY, &pool_size)?;
-    
 
+        // Store pending tx
+        let new_pair = Pair {
+            gas: gas_amount.u128(),
+            address: destination.address,
+            token: token.address.clone(),
+            height: env.block.height,
+            time: env.block.time,
+            bucketed,
+            depositor: depositor.clone(),
+            expiry_height: env.block.height.saturating_add(config.expiry_blocks),
+            payout: payout.clone(),
+            committed,
+        };
 
-    Ok(HandleResponse {
-        messages: msg_list,
-        log: vec![
-            log("tx_code", tx_key_string),
-        ],
-        data: None,
-    })
+
+        PairStore::from_storage(&mut deps.storage).save(&commitment, &new_pair)?;
+        add_pending(&mut deps.storage, &new_pair.depositor, &commitment)?;
+
+        let record = TxRecord {
+            depositor: new_pair.depositor.clone(),
+            status: TxStatus::Pending,
+            height: new_pair.height,
+            time: new_pair.time,
+            closed_height: None,
+            closed_time: None,
+        };
+        RecordStore::from_storage(&mut deps.storage).save(&commitment, &record)?;
+
+
+
+        // Adjust pool size
+        adjust_pool(&mut deps.storage, &new_pair, true)?;
+
+
+        deposit_outputs.push(DepositOutput {
+            id: hex::encode(commitment),
+            tx_key: tx_key_string,
+            amount: gas_amount,
+        });
+    }
+
+
+
+    Ok(deposit_outputs)
 }
 
 
@@ -273,89 +572,164 @@ pub fn seed_wallet<S: Storage, A: Api, Q: Querier>(
 pub fn finalize_seed<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    tx_key: String,
+    tx_keys: Vec<String>,
 ) -> StdResult<HandleResponse> {
 
     let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
 
-
-
-    if !config.active {
-        return Err(StdError::generic_err(
-            "Transfers are currently disabled",
-        ));
+    if !config.operators.contains(&sender_raw) {
+        return Err(ContractError::NotOperator.into());
     }
 
-
-    let tx_data_wrapped: Option<Pair> = may_load(&deps.storage, tx_key.as_bytes())?;
-    let tx_data: Pair;
-    if tx_data_wrapped == None {
-        return Err(StdError::generic_err(
-            "There are no pending transactions with this key.",
-        ));
+    if config.status == ContractStatus::Paused {
+        return Err(ContractError::Paused.into());
     }
-    else {
-        tx_data = tx_data_wrapped.unwrap();
+
+    if tx_keys.is_empty() {
+        return Err(ContractError::NoKeys.into());
     }
 
 
-    let mut msg_list: Vec<CosmosMsg> = vec![];
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
 
-    let snip20_address: HumanAddr = load(&deps.storage, SNIP20_ADDRESS_KEY)?;
-    let callback_code_hash: String = load(&deps.storage, &SNIP20_HASH_KEY)?;
+    // Check every key before anything is closed, so a single bad key fails the whole batch
+    let mut batch: Vec<([u8; 32], Pair)> = Vec::with_capacity(tx_keys.len());
 
+    // Keys may be commitment secrets, so errors name their position rather than echo them
+    for (index, tx_key) in tx_keys.iter().enumerate() {
+        let commitment = commitment_key(tx_key);
 
-    let padding: Option<String> = None;
+        // A key listed twice has no pending tx left the second time
+        let tx_data: Pair = match ReadonlyPairStore::from_storage(&deps.storage).may_load(&commitment)? {
+            Some(pair) if !batch.iter().any(|(c, _)| c == &commitment) => pair,
+            _ => {
+                return Err(ContractError::UnknownBatchKey { index }.into());
+            }
+        };
 
+        if env.block.time < tx_data.time.saturating_add(config.min_delay) {
+            return Err(ContractError::TooEarly { index }.into());
+        }
 
+        batch.push((commitment, tx_data));
+    }
 
-    
 
-    
-    let redeem_msg = RedeemHandleMsg::Redeem {
-        amount: Uint128::from(tx_data.gas),
-        denom: Some("uscrt".to_string()),
-        padding
-    };
+    // A tx only hides among the others of its token, or of its denomination when bucketed,
+    // so none of those may be left below the threshold once the batch is out
+    if config.min_pool_size > 0 {
+        let token_pools: Vec<TokenPool> = load(&deps.storage, TOKEN_POOLS_KEY)?;
+        let buckets: Vec<Bucket> = load(&deps.storage, BUCKETS_KEY)?;
+
+        for (_, tx_data) in batch.iter() {
+            let same_pool = |other: &Pair| {
+                other.token == tx_data.token
+                    && (!tx_data.bucketed || (other.bucketed && other.gas == tx_data.gas))
+            };
+            let leaving = batch.iter().filter(|(_, other)| same_pool(other)).count() as u64;
+
+            let pool_size = if tx_data.bucketed {
+                buckets
+                    .iter()
+                    .find(|b| b.token == tx_data.token && b.amount.u128() == tx_data.gas)
+                    .map_or(0, |b| b.pool_size)
+            } else {
+                token_pools
+                    .iter()
+                    .find(|p| p.token == tx_data.token)
+                    .map_or(0, |p| p.pool_size)
+            };
+
+            if pool_size.saturating_sub(leaving) < config.min_pool_size {
+                return Err(ContractError::PoolTooSmall { min: config.min_pool_size }.into());
+            }
+        }
+    }
 
-    let cosmos_msg = redeem_msg.to_cosmos_msg(
-        callback_code_hash,
-        snip20_address,
-        None,
-    )?;
-    msg_list.push(cosmos_msg);
 
+    let mut pending: Vec<(Pair, Token)> = Vec::with_capacity(batch.len());
+    // Running total to redeem per token, in the order tokens first appear
+    let mut totals: Vec<(Token, u128)> = vec![];
 
+    for (commitment, tx_data) in batch {
+        close_pair(&mut deps.storage, &env, &commitment, &tx_data, TxStatus::Finalized)?;
 
-    let withdrawal_coins: Vec<Coin> = vec![Coin {
-        denom: "uscrt".to_string(),
-        amount: Uint128::from(tx_data.gas),
-    }];
+        let token: Token = find_token(&tokens, &tx_data.token)?;
 
-    let cosmos_msg = CosmosMsg::Bank(BankMsg::Send {
-        from_address: env.contract.address.clone(),
-        to_address: tx_data.address,
-        amount: withdrawal_coins,
-    });
-    msg_list.push(cosmos_msg);
+        if tx_data.payout == Payout::Native {
+            match totals.iter_mut().find(|(t, _)| t.address == token.address) {
+                Some((_, total)) => *total += tx_data.gas,
+                None => totals.push((token.clone(), tx_data.gas)),
+            }
+        }
+
+        pending.push((tx_data, token));
+    }
 
 
-    remove(&mut deps.storage, tx_key.as_bytes());
+    let mut msg_list: Vec<CosmosMsg> = vec![];
+    let mut rng = padding_rng(&deps.storage, &env)?;
+
+
+    // One redeem per snip20 covers the whole batch. Native coins are already held
+    for (token, total) in totals.into_iter().filter(|(t, _)| !t.native) {
+        let redeem_msg = RedeemHandleMsg::Redeem {
+            amount: Uint128::from(total),
+            denom: Some(token.denom.clone()),
+            padding: random_padding(&mut rng),
+        };
+
+        let cosmos_msg = redeem_msg.to_cosmos_msg(
+            token.code_hash,
+            token.address,
+            None,
+        )?;
+        msg_list.push(cosmos_msg);
+    }
 
 
+    for (tx_data, token) in pending.iter() {
+        let amount = Uint128::from(tx_data.gas);
 
+        let cosmos_msg = match &tx_data.payout {
+            Payout::Native => {
+                let withdrawal_coins: Vec<Coin> = vec![Coin {
+                    denom: token.denom.clone(),
+                    amount,
+                }];
 
-    // Adjust pool size
-    let mut pool_size: u16 = load(&deps.storage, POOL_SIZE_KEY)?;
-    pool_size = pool_size - 1;
-    save(&mut deps.storage, POOL_SIZE_KEY, &pool_size)?;
+                CosmosMsg::Bank(BankMsg::Send {
+                    from_address: env.contract.address.clone(),
+                    to_address: tx_data.address.clone(),
+                    amount: withdrawal_coins,
+                })
+            }
+            Payout::Transfer => payout_msg(&env.contract.address, token, tx_data.address.clone(), amount, random_padding(&mut rng))?,
+            Payout::Send { msg } => send_msg(
+                tx_data.address.clone(),
+                amount,
+                msg.clone(),
+                random_padding(&mut rng),
+                BLOCK_SIZE,
+                token.code_hash.clone(),
+                token.address.clone(),
+            )?,
+        };
+        msg_list.push(cosmos_msg);
+    }
 
 
 
     Ok(HandleResponse {
         messages: msg_list,
-        log: vec![],
-        data: None,
+        log: vec![
+            log("finalized", pending.len()),
+        ],
+        data: Some(to_binary(&HandleAnswer::Finalize {
+            status: Success,
+            finalized: pending.len() as u32,
+        })?),
     })
 }
 
@@ -367,62 +741,51 @@ pub fn finalize_seed<S: Storage, A: Api, Q: Querier>(
 
 
 
+/// Deliberately ignores the contract status, so this is also the emergency path for
+/// reclaiming funds while the contract is paused. Commitment deposits always go back to
+/// their depositor, since the operator holds the secret too once it is sent to finalize
 pub fn exit_pool<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
     tx_key: String,
-) -> StdResult<HandleResponse> { 
+) -> StdResult<HandleResponse> {
 
+    let commitment = commitment_key(&tx_key);
 
-    let tx_data_wrapped: Option<Pair> = may_load(&deps.storage, tx_key.as_bytes())?;
-    let tx_data: Pair;
-    if tx_data_wrapped == None {
-        return Err(StdError::generic_err(
-            "There are no pending transactions with this key.",
-        ));
-    }
-    else {
-        tx_data = tx_data_wrapped.unwrap();
-    }
+    let tx_data: Pair = match ReadonlyPairStore::from_storage(&deps.storage).may_load(&commitment)? {
+        Some(pair) => pair,
+        None => {
+            return Err(ContractError::UnknownKey.into());
+        }
+    };
 
 
 
     let mut msg_list: Vec<CosmosMsg> = vec![];
-    
-    let snip20_address: HumanAddr = load(&deps.storage, SNIP20_ADDRESS_KEY)?;
-    let callback_code_hash: String = load(&deps.storage, SNIP20_HASH_KEY)?;
 
-    let padding: Option<String> = None;
-    
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+    let token: Token = find_token(&tokens, &tx_data.token)?;
+
     let amount = Uint128::from(tx_data.gas);
-    let recipient: HumanAddr = env.message.sender;
-    let cosmos_msg = transfer_msg(
-        recipient.clone(),
-        amount,
-        padding,
-        BLOCK_SIZE,
-        callback_code_hash,
-        snip20_address,
-    )?;
+    let recipient: HumanAddr = if tx_data.committed {
+        deps.api.human_address(&tx_data.depositor)?
+    } else {
+        env.message.sender.clone()
+    };
+    let mut rng = padding_rng(&deps.storage, &env)?;
+    let cosmos_msg = payout_msg(&env.contract.address, &token, recipient, amount, random_padding(&mut rng))?;
     msg_list.push(cosmos_msg);
 
 
 
-    remove(&mut deps.storage, tx_key.as_bytes());
-
+    close_pair(&mut deps.storage, &env, &commitment, &tx_data, TxStatus::Exited)?;
 
 
-    // Adjust pool size
-    let mut pool_size: u16 = load(&deps.storage, POOL_SIZE_KEY)?;
-    pool_size = pool_size - 1;
-    save(&mut deps.storage, POOL_SIZE_KEY, &pool_size)?;
-
-    
 
     Ok(HandleResponse {
         messages: msg_list,
         log: vec![],
-        data: None,
+        data: Some(to_binary(&HandleAnswer::Exit { status: Success, refunded: amount })?),
     })
 }
 
@@ -432,63 +795,104 @@ pub fn exit_pool<S: Storage, A: Api, Q: Querier>(
 
 
 
-pub fn new_entropy(env: &Env, seed: &[u8], entropy: &[u8])-> [u8;32]{
-    // 16 here represents the lengths in bytes of the block height and time.
-    let entropy_len = 16 + env.message.sender.len() + entropy.len();
-    let mut rng_entropy = Vec::with_capacity(entropy_len);
-    rng_entropy.extend_from_slice(&env.block.height.to_be_bytes());
-    rng_entropy.extend_from_slice(&env.block.time.to_be_bytes());
-    rng_entropy.extend_from_slice(&env.message.sender.0.as_bytes());
-    rng_entropy.extend_from_slice(entropy);
-
-    let mut rng = Prng::new(seed, &rng_entropy);
+/// The admin is paid in the token itself. The first operator's share is redeemed so it
+/// can cover gas
+pub fn withdraw_fees<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
 
-    rng.rand_bytes()
-}
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
 
+    let is_admin = config.admin == sender_raw;
+    let is_operator = config.operators.first() == Some(&sender_raw);
+    if !is_admin && !is_operator {
+        return Err(ContractError::NotFeeReceiver.into());
+    }
 
 
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+    let mut balances: Vec<FeeBalance> = load(&deps.storage, FEES_KEY)?;
 
+    let mut msg_list: Vec<CosmosMsg> = vec![];
+    let mut rng = padding_rng(&deps.storage, &env)?;
 
+    for balance in balances.iter_mut() {
+        let token: Token = find_token(&tokens, &balance.token)?;
 
+        if is_admin && !balance.admin.is_zero() {
+            let cosmos_msg = payout_msg(&env.contract.address, &token, env.message.sender.clone(), balance.admin, random_padding(&mut rng))?;
+            msg_list.push(cosmos_msg);
 
-// ADMIN COMMANDS
+            balance.admin = Uint128::zero();
+        }
 
-pub fn change_fee<S: Storage, A: Api, Q: Querier>(
-    deps: &mut Extern<S, A, Q>,
-    env: Env,
-    new_fee: Uint128,
-    new_op_share: Uint128
-) -> StdResult<HandleResponse> {
-    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;  
-    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+        if is_operator && !balance.operator.is_zero() {
+            msg_list.extend(operator_fee_msgs(&env.contract.address, &token, env.message.sender.clone(), balance.operator, random_padding(&mut rng))?);
 
-    if config.admin != sender_raw {
-        return Err(StdError::generic_err(
-            "This function is only usable by the Admin",
-        ));
+            balance.operator = Uint128::zero();
+        }
     }
 
-    if new_fee <= new_op_share {
-        return Err(StdError::generic_err(
-            "The operator share must be less than the total fee",
-        ));
+    if msg_list.is_empty() {
+        return Err(ContractError::NoFees.into());
     }
 
+    save(&mut deps.storage, FEES_KEY, &balances)?;
 
-    config.fee = new_fee;
-    config.op_share = new_op_share;
 
 
-    save(&mut deps.storage, CONFIG_KEY, &config)?;
+    Ok(HandleResponse {
+        messages: msg_list,
+        log: vec![
+            log("action", "withdraw_fees"),
+        ],
+        data: Some(to_binary(&HandleAnswer::WithdrawFees { status: Success })?),
+    })
+}
 
 
-    Ok(HandleResponse::default())
-}
 
+/// Pays the operator's share of one token's fees in the native denom, redeeming it first
+/// unless it is a native coin already
+fn operator_fee_msgs(
+    contract_address: &HumanAddr,
+    token: &Token,
+    recipient: HumanAddr,
+    amount: Uint128,
+    padding: Option<String>,
+) -> StdResult<Vec<CosmosMsg>> {
+    let mut msg_list: Vec<CosmosMsg> = vec![];
+
+    if !token.native {
+        let redeem_msg = RedeemHandleMsg::Redeem {
+            amount,
+            denom: Some(token.denom.clone()),
+            padding,
+        };
+
+        let cosmos_msg = redeem_msg.to_cosmos_msg(
+            token.code_hash.clone(),
+            token.address.clone(),
+            None,
+        )?;
+        msg_list.push(cosmos_msg);
+    }
 
+    let withdrawal_coins: Vec<Coin> = vec![Coin {
+        denom: token.denom.clone(),
+        amount,
+    }];
 
+    msg_list.push(CosmosMsg::Bank(BankMsg::Send {
+        from_address: contract_address.clone(),
+        to_address: recipient,
+        amount: withdrawal_coins,
+    }));
 
+    Ok(msg_list)
+}
 
 
 
@@ -496,73 +900,2057 @@ pub fn change_fee<S: Storage, A: Api, Q: Querier>(
 
 
 
-pub fn change_admin<S: Storage, A: Api, Q: Querier>(
+/// Refunds the expired txs in a page of the depositor's deposits to the depositor. Like
+/// exits this ignores the contract status, so funds can't be stuck by an operator that
+/// never finalizes. Reclaimed txs leave the list, so repeating the call with the first
+/// page keeps making progress
+pub fn reclaim_expired<S: Storage, A: Api, Q: Querier>(
     deps: &mut Extern<S, A, Q>,
     env: Env,
-    new_admin: HumanAddr
+    depositor: HumanAddr,
+    page: u32,
+    page_size: u32,
 ) -> StdResult<HandleResponse> {
-    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
-    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
 
-    if config.admin != sender_raw {
-        return Err(StdError::generic_err(
-            "This function is only usable by the Admin",
-        ));
-    }
+    let depositor_raw = deps.api.canonical_address(&depositor)?;
+    // Refunds go to the stored depositor, not whatever spelling of it the sender used
+    let depositor = deps.api.human_address(&depositor_raw)?;
+    let deposits = ReadonlyCommitmentList::deposits(&deps.storage, &depositor_raw);
+    let commitments = deposits.page(u64::from(page).saturating_mul(u64::from(page_size)), u64::from(page_size))?;
 
-    config.admin = deps.api.canonical_address(&new_admin)?;
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
 
-    save(&mut deps.storage, CONFIG_KEY, &config)?;
+    // Running total refunded per token, in the order tokens first appear
+    let mut totals: Vec<(Token, u128)> = vec![];
+    let mut reclaimed: usize = 0;
 
+    for commitment in commitments {
+        let tx_data: Pair = ReadonlyPairStore::from_storage(&deps.storage).load(&commitment)?;
+        if env.block.height < tx_data.expiry_height {
+            continue;
+        }
 
+        close_pair(&mut deps.storage, &env, &commitment, &tx_data, TxStatus::Reclaimed)?;
 
-    Ok(HandleResponse::default())
-}
+        let token: Token = find_token(&tokens, &tx_data.token)?;
+        match totals.iter_mut().find(|(t, _)| t.address == token.address) {
+            Some((_, total)) => *total += tx_data.gas,
+            None => totals.push((token, tx_data.gas)),
+        }
+        reclaimed += 1;
+    }
 
+    if reclaimed == 0 {
+        return Err(ContractError::NothingExpired.into());
+    }
 
 
 
+    let mut msg_list: Vec<CosmosMsg> = vec![];
+    let mut rng = padding_rng(&deps.storage, &env)?;
 
-pub fn query<S: Storage, A: Api, Q: Querier>(
-    deps: &Extern<S, A, Q>,
-    msg: QueryMsg,
-) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetConfig {} => to_binary(&query_config(deps)?),
-        QueryMsg::GetExists { tx_key } => to_binary(&query_tx_exists(deps, tx_key)?),
-        QueryMsg::GetPoolSize {} => to_binary(&query_pool_size(deps)?),
+    for (token, total) in totals {
+        let cosmos_msg = payout_msg(&env.contract.address, &token, depositor.clone(), Uint128::from(total), random_padding(&mut rng))?;
+        msg_list.push(cosmos_msg);
     }
-}
 
-fn query_config<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<ConfigResponse> {
-    let config: Config = load(&deps.storage, CONFIG_KEY)?;
 
 
-    Ok(ConfigResponse { active: config.active, fee: config.fee })
+    Ok(HandleResponse {
+        messages: msg_list,
+        log: vec![
+            log("reclaimed", reclaimed),
+        ],
+        data: Some(to_binary(&HandleAnswer::ReclaimExpired {
+            status: Success,
+            reclaimed: reclaimed as u32,
+        })?),
+    })
+}
+
+
+
+
+
+
+
+pub fn new_entropy(env: &Env, seed: &[u8], entropy: &[u8])-> [u8;32]{
+    // 16 here represents the lengths in bytes of the block height and time.
+    let entropy_len = 16 + env.message.sender.len() + entropy.len();
+    let mut rng_entropy = Vec::with_capacity(entropy_len);
+    rng_entropy.extend_from_slice(&env.block.height.to_be_bytes());
+    rng_entropy.extend_from_slice(&env.block.time.to_be_bytes());
+    rng_entropy.extend_from_slice(env.message.sender.0.as_bytes());
+    rng_entropy.extend_from_slice(entropy);
+
+    let mut rng = Prng::new(seed, &rng_entropy);
+
+    rng.rand_bytes()
+}
+
+
+/// Generator for the padding of outgoing snip20 messages. It is separate from the key ratchet,
+/// which stays untouched
+fn padding_rng<S: ReadonlyStorage>(storage: &S, env: &Env) -> StdResult<Prng> {
+    let prng_seed: Vec<u8> = load(storage, PRNG_SEED_KEY)?;
+
+    Ok(Prng::new(&prng_seed, &new_entropy(env, &prng_seed, b"padding")))
+}
+
+/// Up to a block of random padding, on top of the padding to BLOCK_SIZE, so equal actions
+/// don't always produce messages of equal size
+fn random_padding(rng: &mut Prng) -> Option<String> {
+    let bytes = rng.rand_bytes();
+    let len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize % BLOCK_SIZE;
+
+    Some(" ".repeat(len))
+}
+
+
+
+/// Converts the operator list, which may not be empty since the first operator is paid the
+/// operator fee
+fn canonicalize_operators<A: Api>(api: &A, operators: &[HumanAddr]) -> StdResult<Vec<CanonicalAddr>> {
+    if operators.is_empty() {
+        return Err(ContractError::NoOperators.into());
+    }
+
+    operators
+        .iter()
+        .map(|operator| api.canonical_address(operator))
+        .collect()
+}
+
+
+
+/// Pending txs are stored under the hash of their exit key, so only whoever holds the key
+/// (or the secret behind a commitment) can name them
+fn commitment_key(tx_key: &str) -> [u8; 32] {
+    sha_256(tx_key.as_bytes())
+}
+
+
+
+/// Decodes a depositor supplied commitment, the hex encoded sha256 of their secret
+fn parse_commitment(commitment: &str) -> StdResult<[u8; 32]> {
+    let bytes = hex::decode(commitment).map_err(|_| StdError::from(ContractError::InvalidCommitment))?;
+
+    if bytes.len() != 32 {
+        return Err(ContractError::InvalidCommitment.into());
+    }
+
+    let mut result = [0u8; 32];
+    result.copy_from_slice(&bytes);
+    Ok(result)
+}
+
+
+
+/// Marks a tx that just left the pool as finalized or exited
+fn close_record<S: Storage>(
+    storage: &mut S,
+    env: &Env,
+    commitment: &[u8],
+    status: TxStatus,
+) -> StdResult<()> {
+    let mut record_store = RecordStore::from_storage(storage);
+    let mut record: TxRecord = record_store
+        .may_load(commitment)?
+        .ok_or_else(|| StdError::not_found("TxRecord"))?;
+
+    record.status = status;
+    record.closed_height = Some(env.block.height);
+    record.closed_time = Some(env.block.time);
+
+    record_store.save(commitment, &record)
+}
+
+
+
+/// Takes a pending tx out of the pool, its indexes and its bucket, recording how it left
+fn close_pair<S: Storage>(
+    storage: &mut S,
+    env: &Env,
+    commitment: &[u8; 32],
+    pair: &Pair,
+    status: TxStatus,
+) -> StdResult<()> {
+    PairStore::from_storage(storage).remove(commitment);
+    remove_pending(storage, &pair.depositor, commitment)?;
+    close_record(storage, env, commitment, status)?;
+
+    adjust_pool(storage, pair, false)
+}
+
+
+
+/// Deposits must always be able to expire, or funds could be stuck forever
+fn validate_expiry(expiry_blocks: u64) -> StdResult<u64> {
+    if expiry_blocks == 0 {
+        return Err(ContractError::InvalidExpiry.into());
+    }
+
+    Ok(expiry_blocks)
+}
+
+
+
+/// Fee charged on a deposit of the given amount
+fn compute_fee(schedule: &FeeSchedule, amount: Uint128) -> StdResult<Uint128> {
+    // The highest tier the amount reaches replaces the base rate
+    let (flat, bps) = match schedule.tiers.iter().rev().find(|t| t.min_amount <= amount) {
+        Some(tier) => (tier.flat, tier.bps),
+        None => (schedule.flat, schedule.bps),
+    };
+
+    let fee = flat
+        .u128()
+        .checked_add(bps_of(amount, bps)?)
+        .ok_or_else(|| StdError::from(ContractError::FeeOverflow))?;
+
+    let mut fee = Uint128(fee).max(schedule.min);
+    if let Some(max) = schedule.max {
+        fee = fee.min(max);
+    }
+
+    Ok(fee)
+}
+
+
+
+/// The given basis points of an amount, rounded down
+fn bps_of(amount: Uint128, bps: u16) -> StdResult<u128> {
+    amount
+        .u128()
+        .checked_mul(bps as u128)
+        .map(|scaled| scaled / MAX_BPS as u128)
+        .ok_or_else(|| StdError::from(ContractError::FeeOverflow))
+}
+
+
+
+fn validate_fee_schedule(schedule: &FeeSchedule) -> StdResult<()> {
+    // A fee of the whole deposit would leave nothing to send
+    if schedule.bps >= MAX_BPS || schedule.tiers.iter().any(|t| t.bps >= MAX_BPS) {
+        return Err(ContractError::FeeRateTooHigh { max: MAX_BPS }.into());
+    }
+
+    if schedule.operator_share_bps > MAX_BPS {
+        return Err(ContractError::OperatorShareTooHigh { max: MAX_BPS }.into());
+    }
+
+    if let Some(max) = schedule.max {
+        if max < schedule.min {
+            return Err(ContractError::FeeBoundsInverted.into());
+        }
+    }
+
+    if schedule.tiers.windows(2).any(|w| w[0].min_amount >= w[1].min_amount) {
+        return Err(ContractError::UnsortedFeeTiers.into());
+    }
+
+    Ok(())
+}
+
+
+
+/// Divides the gas between destinations by weight. Shares are rounded down to a
+/// denomination when the token has them, and whatever doesn't fit is returned as the
+/// remainder. Otherwise the last destination takes the rounding dust
+fn split_gas(
+    token: &Token,
+    gas: Uint128,
+    destinations: &[Destination],
+) -> StdResult<(Vec<Uint128>, Uint128)> {
+    if destinations.is_empty() || destinations.len() > MAX_DESTINATIONS {
+        return Err(ContractError::DestinationCount { max: MAX_DESTINATIONS }.into());
+    }
+
+    if destinations.iter().any(|d| d.weight.is_zero()) {
+        return Err(ContractError::ZeroWeight.into());
+    }
+
+    let total_weight: u128 = destinations.iter().try_fold(0u128, |total, d| {
+        total.checked_add(d.weight.u128()).ok_or_else(|| StdError::from(ContractError::DepositTooLarge))
+    })?;
+    let bucketed = !token.denominations.is_empty();
+
+    let mut shares: Vec<Uint128> = Vec::with_capacity(destinations.len());
+    let mut remainder: u128 = gas.u128();
+
+    for (index, destination) in destinations.iter().enumerate() {
+        let mut share = if !bucketed && index + 1 == destinations.len() {
+            remainder
+        } else {
+            gas.u128()
+                .checked_mul(destination.weight.u128())
+                .ok_or_else(|| StdError::from(ContractError::DepositTooLarge))?
+                / total_weight
+        };
+
+        if bucketed {
+            share = match token.denominations.iter().find(|d| d.u128() <= share) {
+                Some(denomination) => denomination.u128(),
+                None => {
+                    return Err(ContractError::BelowDenomination.into());
+                }
+            };
+        }
+
+        if share == 0 {
+            return Err(ContractError::EmptyOutput.into());
+        }
+
+        remainder -= share;
+        shares.push(Uint128(share));
+    }
+
+    Ok((shares, Uint128(remainder)))
+}
+
+
+
+/// Sends an amount held by the contract, as a snip20 transfer or native coins
+fn payout_msg(
+    contract_address: &HumanAddr,
+    token: &Token,
+    recipient: HumanAddr,
+    amount: Uint128,
+    padding: Option<String>,
+) -> StdResult<CosmosMsg> {
+    if token.native {
+        return Ok(CosmosMsg::Bank(BankMsg::Send {
+            from_address: contract_address.clone(),
+            to_address: recipient,
+            amount: vec![Coin {
+                denom: token.denom.clone(),
+                amount,
+            }],
+        }));
+    }
+
+    transfer_msg(
+        recipient,
+        amount,
+        padding,
+        BLOCK_SIZE,
+        token.code_hash.clone(),
+        token.address.clone(),
+    )
+}
+
+
+
+/// Returns the registered token at the given address, whether or not it is still accepted
+fn find_token(tokens: &[Token], address: &HumanAddr) -> StdResult<Token> {
+    tokens
+        .iter()
+        .find(|t| &t.address == address)
+        .cloned()
+        .ok_or_else(|| StdError::from(ContractError::UnknownToken { token: address.clone() }))
+}
+
+
+
+/// Adds or re-enables a token in the registry and returns the message registering our
+/// receive hook with it. Native coins need no hook
+fn register_token(
+    tokens: &mut Vec<Token>,
+    env: &Env,
+    token_info: TokenInfo,
+    padding: Option<String>,
+) -> StdResult<Option<CosmosMsg>> {
+    validate_fee_schedule(&token_info.fee)?;
+
+    if token_info.native && token_info.address.as_str() != token_info.denom {
+        return Err(ContractError::NativeAddressNotDenom.into());
+    }
+
+    let token = Token {
+        address: token_info.address,
+        code_hash: token_info.code_hash,
+        denom: token_info.denom,
+        native: token_info.native,
+        fee: token_info.fee,
+        denominations: validate_denominations(token_info.denominations)?,
+        accepted: true,
+    };
+
+    match tokens.iter_mut().find(|t| t.address == token.address) {
+        Some(existing) if existing.accepted => {
+            return Err(ContractError::TokenAlreadyAccepted { token: token.address }.into());
+        }
+        Some(existing) => *existing = token.clone(),
+        None => tokens.push(token.clone()),
+    }
+
+    if token.native {
+        return Ok(None);
+    }
+
+    register_receive_msg(
+        env.contract_code_hash.clone(),
+        padding,
+        BLOCK_SIZE,
+        token.code_hash,
+        token.address
+    ).map(Some)
+}
+
+
+
+
+
+
+
+/// Checks the denominations are non-zero and unique, returning them largest first
+fn validate_denominations(mut denominations: Vec<Uint128>) -> StdResult<Vec<Uint128>> {
+    if denominations.iter().any(|d| d.is_zero()) {
+        return Err(ContractError::ZeroDenomination.into());
+    }
+
+    denominations.sort_unstable_by(|a, b| b.cmp(a));
+
+    let count = denominations.len();
+    denominations.dedup();
+    if denominations.len() != count {
+        return Err(ContractError::DuplicateDenomination.into());
+    }
+
+    Ok(denominations)
+}
+
+
+
+/// Credits the fee on a deposit to the admin and operator balances of its token
+fn accrue_fees<S: Storage>(
+    storage: &mut S,
+    token: &HumanAddr,
+    admin_share: Uint128,
+    operator_share: Uint128,
+) -> StdResult<()> {
+    let mut balances: Vec<FeeBalance> = load(storage, FEES_KEY)?;
+
+    let index = match balances.iter().position(|b| &b.token == token) {
+        Some(index) => index,
+        None => {
+            balances.push(FeeBalance {
+                token: token.clone(),
+                admin: Uint128::zero(),
+                operator: Uint128::zero(),
+                admin_lifetime: Uint128::zero(),
+                operator_lifetime: Uint128::zero(),
+            });
+            balances.len() - 1
+        }
+    };
+
+    let balance = &mut balances[index];
+    balance.admin = checked_add(balance.admin, admin_share)?;
+    balance.operator = checked_add(balance.operator, operator_share)?;
+    balance.admin_lifetime = checked_add(balance.admin_lifetime, admin_share)?;
+    balance.operator_lifetime = checked_add(balance.operator_lifetime, operator_share)?;
+
+    save(storage, FEES_KEY, &balances)
+}
+
+
+
+fn checked_add(a: Uint128, b: Uint128) -> StdResult<Uint128> {
+    a.u128()
+        .checked_add(b.u128())
+        .map(Uint128)
+        .ok_or_else(|| StdError::from(ContractError::FeeBalanceOverflow))
+}
+
+
+
+/// Counts one pending tx into or out of the pool size, the total value locked, the
+/// totals of its token and the bucket of its denomination
+fn adjust_pool<S: Storage>(
+    storage: &mut S,
+    pair: &Pair,
+    increase: bool,
+) -> StdResult<()> {
+    let pool_size: u64 = load(storage, POOL_SIZE_KEY)?;
+    save(storage, POOL_SIZE_KEY, &checked_count(pool_size, increase)?)?;
+
+    let tvl: Uint128 = load(storage, TVL_KEY)?;
+    save(storage, TVL_KEY, &Uint128(checked_adjust(tvl.u128(), pair.gas, increase)?))?;
+
+
+    let mut token_pools: Vec<TokenPool> = load(storage, TOKEN_POOLS_KEY)?;
+
+    let index = match token_pools.iter().position(|p| p.token == pair.token) {
+        Some(index) => index,
+        None => {
+            token_pools.push(TokenPool {
+                token: pair.token.clone(),
+                pool_size: 0,
+                amount: Uint128::zero(),
+            });
+            token_pools.len() - 1
+        }
+    };
+
+    let token_pool = &mut token_pools[index];
+    token_pool.pool_size = checked_count(token_pool.pool_size, increase)?;
+    token_pool.amount = Uint128(checked_adjust(token_pool.amount.u128(), pair.gas, increase)?);
+
+    if token_pool.pool_size == 0 {
+        token_pools.remove(index);
+    }
+
+    save(storage, TOKEN_POOLS_KEY, &token_pools)?;
+
+
+    if pair.bucketed {
+        adjust_bucket(storage, &pair.token, Uint128::from(pair.gas), increase)?;
+    }
+
+    Ok(())
+}
+
+
+
+/// Steps a counter by one without wrapping, so a double removal fails instead of
+/// corrupting the count
+fn checked_count(count: u64, increase: bool) -> StdResult<u64> {
+    let count = if increase { count.checked_add(1) } else { count.checked_sub(1) };
+    count.ok_or_else(|| StdError::from(ContractError::PoolSizeOutOfRange))
+}
+
+
+
+fn checked_adjust(total: u128, amount: u128, increase: bool) -> StdResult<u128> {
+    let total = if increase { total.checked_add(amount) } else { total.checked_sub(amount) };
+    total.ok_or_else(|| StdError::from(ContractError::PoolValueOutOfRange))
+}
+
+
+
+/// Adds or removes one pending tx from the bucket of its denomination
+fn adjust_bucket<S: Storage>(
+    storage: &mut S,
+    token: &HumanAddr,
+    amount: Uint128,
+    increase: bool,
+) -> StdResult<()> {
+    let mut buckets: Vec<Bucket> = load(storage, BUCKETS_KEY)?;
+
+    match buckets.iter().position(|b| &b.token == token && b.amount == amount) {
+        Some(index) if increase => buckets[index].pool_size = checked_count(buckets[index].pool_size, true)?,
+        Some(index) => {
+            buckets[index].pool_size = checked_count(buckets[index].pool_size, false)?;
+
+            // Empty buckets are dropped so retired denominations don't linger
+            if buckets[index].pool_size == 0 {
+                buckets.remove(index);
+            }
+        }
+        None if increase => buckets.push(Bucket {
+            token: token.clone(),
+            amount,
+            pool_size: 1,
+        }),
+        None => {
+            return Err(ContractError::EmptyBucket.into());
+        }
+    }
+
+    save(storage, BUCKETS_KEY, &buckets)
+}
+
+
+
+
+
+
+
+// ADMIN COMMANDS
+
+pub fn change_fee<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: HumanAddr,
+    fee: FeeSchedule
+) -> StdResult<HandleResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    validate_fee_schedule(&fee)?;
+
+
+    let mut tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+    let token_config = match tokens.iter_mut().find(|t| t.address == token) {
+        Some(token_config) => token_config,
+        None => {
+            return Err(ContractError::UnknownToken { token }.into());
+        }
+    };
+
+    token_config.fee = fee;
+
+
+    save(&mut deps.storage, TOKENS_KEY, &tokens)?;
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ChangeFee { status: Success })?),
+    })
+}
+
+
+
+
+
+
+
+
+
+
+
+pub fn change_admin<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    new_admin: HumanAddr
+) -> StdResult<HandleResponse> {
+    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    config.pending_admin = Some(deps.api.canonical_address(&new_admin)?);
+
+    save(&mut deps.storage, CONFIG_KEY, &config)?;
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![
+            log("action", "change_admin"),
+            log("pending_admin", new_admin),
+        ],
+        data: Some(to_binary(&HandleAnswer::ChangeAdmin { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn accept_admin<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+) -> StdResult<HandleResponse> {
+    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.pending_admin != Some(sender_raw.clone()) {
+        return Err(ContractError::NotPendingAdmin.into());
+    }
+
+    config.admin = sender_raw;
+    config.pending_admin = None;
+
+    save(&mut deps.storage, CONFIG_KEY, &config)?;
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![
+            log("action", "accept_admin"),
+            log("admin", env.message.sender),
+        ],
+        data: Some(to_binary(&HandleAnswer::AcceptAdmin { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn change_operator<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    new_operators: Vec<HumanAddr>
+) -> StdResult<HandleResponse> {
+    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    let new_operators = canonicalize_operators(&deps.api, &new_operators)?;
+
+    // Fees accrue to whoever is first at withdrawal, so a new fee receiver is only owed
+    // what accrues from now on. The previous one is paid out here
+    let mut msg_list: Vec<CosmosMsg> = vec![];
+    if config.operators.first() != new_operators.first() {
+        if let Some(previous) = config.operators.first() {
+            let previous = deps.api.human_address(previous)?;
+            let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+            let mut balances: Vec<FeeBalance> = load(&deps.storage, FEES_KEY)?;
+            let mut rng = padding_rng(&deps.storage, &env)?;
+
+            for balance in balances.iter_mut().filter(|b| !b.operator.is_zero()) {
+                let token: Token = find_token(&tokens, &balance.token)?;
+                msg_list.extend(operator_fee_msgs(&env.contract.address, &token, previous.clone(), balance.operator, random_padding(&mut rng))?);
+
+                balance.operator = Uint128::zero();
+            }
+
+            save(&mut deps.storage, FEES_KEY, &balances)?;
+        }
+    }
+
+    config.operators = new_operators;
+
+    save(&mut deps.storage, CONFIG_KEY, &config)?;
+
+
+
+    Ok(HandleResponse {
+        messages: msg_list,
+        log: vec![
+            log("action", "change_operator"),
+        ],
+        data: Some(to_binary(&HandleAnswer::ChangeOperator { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn set_active<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    status: ContractStatus
+) -> StdResult<HandleResponse> {
+    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    let previous = config.status;
+    config.status = status;
+
+    save(&mut deps.storage, CONFIG_KEY, &config)?;
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![
+            log("action", "set_active"),
+            log("previous_status", format!("{:?}", previous)),
+            log("status", format!("{:?}", status)),
+        ],
+        data: Some(to_binary(&HandleAnswer::SetActive { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn change_release_conditions<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    min_delay: u64,
+    min_pool_size: u64,
+    expiry_blocks: u64
+) -> StdResult<HandleResponse> {
+    let mut config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    config.min_delay = min_delay;
+    config.min_pool_size = min_pool_size;
+    config.expiry_blocks = validate_expiry(expiry_blocks)?;
+
+    save(&mut deps.storage, CONFIG_KEY, &config)?;
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::ChangeReleaseConditions { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn add_token<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: TokenInfo
+) -> StdResult<HandleResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    let mut tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+    let mut rng = padding_rng(&deps.storage, &env)?;
+    let cosmos_msg = register_token(&mut tokens, &env, token, random_padding(&mut rng))?;
+
+    save(&mut deps.storage, TOKENS_KEY, &tokens)?;
+
+
+
+    Ok(HandleResponse {
+        messages: cosmos_msg.into_iter().collect(),
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::AddToken { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn remove_token<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: HumanAddr
+) -> StdResult<HandleResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    let mut tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+    match tokens.iter_mut().find(|t| t.address == token) {
+        Some(token_config) => token_config.accepted = false,
+        None => {
+            return Err(ContractError::UnknownToken { token }.into());
+        }
+    }
+
+    save(&mut deps.storage, TOKENS_KEY, &tokens)?;
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RemoveToken { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn set_denominations<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    token: HumanAddr,
+    denominations: Vec<Uint128>
+) -> StdResult<HandleResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+
+    if config.admin != sender_raw {
+        return Err(ContractError::NotAdmin.into());
+    }
+
+    let denominations = validate_denominations(denominations)?;
+
+    let mut tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+    match tokens.iter_mut().find(|t| t.address == token) {
+        Some(token_config) => token_config.denominations = denominations,
+        None => {
+            return Err(ContractError::UnknownToken { token }.into());
+        }
+    }
+
+    save(&mut deps.storage, TOKENS_KEY, &tokens)?;
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetDenominations { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn create_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    entropy: String
+) -> StdResult<HandleResponse> {
+    let prng_seed: Vec<u8> = load(&deps.storage, PRNG_SEED_KEY)?;
+
+    let key = ViewingKey::new(&new_entropy(&env, &prng_seed, entropy.as_bytes()));
+
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+    ViewingKeyStore::from_storage(&mut deps.storage).save(&sender_raw, &key);
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::CreateViewingKey { status: Success, key })?),
+    })
+}
+
+
+
+
+
+pub fn set_viewing_key<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    key: String
+) -> StdResult<HandleResponse> {
+    let key = ViewingKey(key);
+
+    let sender_raw = deps.api.canonical_address(&env.message.sender)?;
+    ViewingKeyStore::from_storage(&mut deps.storage).save(&sender_raw, &key);
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::SetViewingKey { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn revoke_permit<S: Storage, A: Api, Q: Querier>(
+    deps: &mut Extern<S, A, Q>,
+    env: Env,
+    permit_name: String
+) -> StdResult<HandleResponse> {
+    RevokedPermits::revoke_permit(
+        &mut deps.storage,
+        PREFIX_REVOKED_PERMITS,
+        &env.message.sender,
+        &permit_name,
+    );
+
+
+
+    Ok(HandleResponse {
+        messages: vec![],
+        log: vec![],
+        data: Some(to_binary(&HandleAnswer::RevokePermit { status: Success })?),
+    })
+}
+
+
+
+
+
+pub fn query<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    msg: QueryMsg,
+) -> QueryResult {
+    let response = match msg {
+        QueryMsg::GetConfig {} => to_binary(&query_config(deps)?),
+        QueryMsg::GetPoolSize {} => to_binary(&query_pool_size(deps)?),
+        QueryMsg::GetAdmin {} => to_binary(&query_admin(deps)?),
+        QueryMsg::GetFees {} => to_binary(&query_fees(deps)?),
+        QueryMsg::ContractInfo {} => to_binary(&query_contract_info(deps)?),
+        QueryMsg::PendingSeeds { address, key, page, page_size } => {
+            let viewer = authenticate(deps, &address, key)?;
+            to_binary(&query_pending_seeds(deps, &viewer, page.unwrap_or(0), page_size)?)
+        }
+        QueryMsg::SeedStatus { address, key, id } => {
+            let viewer = authenticate(deps, &address, key)?;
+            to_binary(&query_seed_status(deps, &viewer, id)?)
+        }
+        QueryMsg::WithPermit { permit, query } => permit_queries(deps, permit, query),
+    };
+
+    pad_query_result(response, BLOCK_SIZE)
 }
 
 
 
-fn query_tx_exists<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>, tx_key: String,) -> StdResult<ExistsResponse> {
-    
-    let exists: bool;
+fn permit_queries<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    permit: Permit,
+    query: QueryWithPermit,
+) -> StdResult<Binary> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+
+    // Checks the signature, that the permit names this contract, and that it isn't revoked
+    let account = validate(deps, PREFIX_REVOKED_PERMITS, &permit, config.contract_address)?;
+    let viewer = deps.api.canonical_address(&account)?;
+
+    let owner = permit.check_permission(&Permission::Owner);
+
+    match query {
+        QueryWithPermit::PendingSeeds { page, page_size } => {
+            if !owner && !permit.check_permission(&Permission::Balance) {
+                return Err(ContractError::NoPermission {
+                    query: "pending seeds",
+                    permissions: permit.params.permissions,
+                }.into());
+            }
+
+            to_binary(&query_pending_seeds(deps, &viewer, page.unwrap_or(0), page_size)?)
+        }
+        QueryWithPermit::SeedStatus { id } => {
+            if !owner && !permit.check_permission(&Permission::History) {
+                return Err(ContractError::NoPermission {
+                    query: "seed status",
+                    permissions: permit.params.permissions,
+                }.into());
+            }
 
-    let tx_data_wrapped: Option<Pair> = may_load(&deps.storage, tx_key.as_bytes())?;
-    if tx_data_wrapped == None {
-        exists = false;
+            to_binary(&query_seed_status(deps, &viewer, id)?)
+        }
     }
-    else {
-        exists = true;
+}
+
+
+
+/// Checks the viewing key given for an address, returning the canonical address
+fn authenticate<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    address: &HumanAddr,
+    key: String,
+) -> StdResult<CanonicalAddr> {
+    let canonical_addr = deps.api.canonical_address(address)?;
+    let expected_key = ReadonlyViewingKeyStore::from_storage(&deps.storage).load(&canonical_addr);
+    let key = ViewingKey(key);
+
+    match expected_key {
+        Some(expected_key) if key.check_viewing_key(&expected_key) => Ok(canonical_addr),
+        Some(_) => Err(ContractError::WrongViewingKey.into()),
+        None => {
+            // Checking against a dummy hash takes as long as a real check, so timing
+            // doesn't reveal whether the address has a key
+            key.check_viewing_key(&[0u8; VIEWING_KEY_SIZE]);
+            Err(ContractError::WrongViewingKey.into())
+        }
     }
+}
+
+fn query_config<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<ConfigResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+    let tokens: Vec<Token> = load(&deps.storage, TOKENS_KEY)?;
+
+    let tokens: Vec<TokenResponse> = tokens
+        .into_iter()
+        .filter(|t| t.accepted)
+        .map(|t| TokenResponse {
+            address: t.address,
+            denom: t.denom,
+            native: t.native,
+            fee: t.fee,
+            denominations: t.denominations,
+        })
+        .collect();
+
+
+    Ok(ConfigResponse {
+        status: config.status,
+        tokens,
+        min_delay: config.min_delay,
+        min_pool_size: config.min_pool_size,
+        expiry_blocks: config.expiry_blocks,
+    })
+}
+
+
+
+fn query_admin<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<AdminResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+
+    let pending_admin = match config.pending_admin {
+        Some(pending_admin) => Some(deps.api.human_address(&pending_admin)?),
+        None => None,
+    };
+
+    let operators = config
+        .operators
+        .iter()
+        .map(|operator| deps.api.human_address(operator))
+        .collect::<StdResult<Vec<HumanAddr>>>()?;
+
+
+    Ok(AdminResponse {
+        admin: deps.api.human_address(&config.admin)?,
+        pending_admin,
+        operators,
+    })
+}
+
+
+
+fn query_contract_info<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<ContractInfoResponse> {
+    let contract_version: ContractVersion = load(&deps.storage, CONTRACT_VERSION_KEY)?;
+    let storage_version: u32 = load(&deps.storage, STORAGE_VERSION_KEY)?;
+
+
+    Ok(ContractInfoResponse {
+        contract: contract_version.contract,
+        version: contract_version.version,
+        storage_version,
+    })
+}
+
+
+
+fn query_pending_seeds<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    viewer: &CanonicalAddr,
+    page: u32,
+    page_size: u32,
+) -> StdResult<PendingSeedsResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+
+    // Operators see the whole pool, everyone else only their own deposits
+    let list = if config.operators.contains(viewer) {
+        ReadonlyCommitmentList::pending(&deps.storage)
+    } else {
+        ReadonlyCommitmentList::deposits(&deps.storage, viewer)
+    };
+    let commitments = list.page(u64::from(page).saturating_mul(u64::from(page_size)), u64::from(page_size))?;
+
+    let pair_store = ReadonlyPairStore::from_storage(&deps.storage);
+
+    let seeds = commitments
+        .iter()
+        .map(|commitment| {
+            let pair: Pair = pair_store.load(commitment)?;
+
+            Ok(PendingSeed {
+                id: hex::encode(commitment),
+                token: pair.token,
+                amount: Uint128::from(pair.gas),
+                destination: pair.address,
+                height: pair.height,
+                time: pair.time,
+                expiry_height: pair.expiry_height,
+            })
+        })
+        .collect::<StdResult<Vec<PendingSeed>>>()?;
+
+
+    Ok(PendingSeedsResponse {
+        seeds,
+        total: list.len()?,
+    })
+}
+
+
+
+fn query_seed_status<S: Storage, A: Api, Q: Querier>(
+    deps: &Extern<S, A, Q>,
+    viewer: &CanonicalAddr,
+    id: String,
+) -> StdResult<SeedStatusResponse> {
+    let config: Config = load(&deps.storage, CONFIG_KEY)?;
+
+    let record: Option<TxRecord> = match hex::decode(&id) {
+        Ok(commitment) => ReadonlyRecordStore::from_storage(&deps.storage).may_load(&commitment)?,
+        Err(_) => None,
+    };
+
+    // Unknown ids and other depositors' txs get the same answer, so the query can't be
+    // used to probe the pool
+    let record = match record {
+        Some(record) if record.depositor == *viewer || config.operators.contains(viewer) => record,
+        _ => {
+            return Err(ContractError::UnknownId.into());
+        }
+    };
+
+
+    Ok(SeedStatusResponse {
+        status: record.status,
+        deposit_height: record.height,
+        deposit_time: record.time,
+        closed_height: record.closed_height,
+        closed_time: record.closed_time,
+    })
+}
+
+
+fn query_fees<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<FeesResponse> {
+    let balances: Vec<FeeBalance> = load(&deps.storage, FEES_KEY)?;
 
-    Ok(ExistsResponse { exists })
+    let fees: Vec<FeeBalanceResponse> = balances
+        .into_iter()
+        .map(|b| FeeBalanceResponse {
+            token: b.token,
+            admin_accrued: b.admin,
+            operator_accrued: b.operator,
+            admin_lifetime: b.admin_lifetime,
+            operator_lifetime: b.operator_lifetime,
+        })
+        .collect();
+
+
+    Ok(FeesResponse { fees })
 }
 
 
+
 fn query_pool_size<S: Storage, A: Api, Q: Querier>(deps: &Extern<S, A, Q>) -> StdResult<PoolSizeResponse> {
-    
-    let pool_size: u16 = load(&deps.storage, POOL_SIZE_KEY)?;
 
+    let pool_size: u64 = load(&deps.storage, POOL_SIZE_KEY)?;
+    let tvl: Uint128 = load(&deps.storage, TVL_KEY)?;
+    let token_pools: Vec<TokenPool> = load(&deps.storage, TOKEN_POOLS_KEY)?;
+    let buckets: Vec<Bucket> = load(&deps.storage, BUCKETS_KEY)?;
+
+    let tokens: Vec<TokenPoolResponse> = token_pools
+        .into_iter()
+        .map(|p| TokenPoolResponse { token: p.token, pool_size: p.pool_size, amount: p.amount })
+        .collect();
+
+    let buckets: Vec<BucketResponse> = buckets
+        .into_iter()
+        .map(|b| BucketResponse { token: b.token, amount: b.amount, pool_size: b.pool_size })
+        .collect();
+
+
+    Ok(PoolSizeResponse { pool_size, tvl, tokens, buckets })
+}
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::FeeTier;
+    use cosmwasm_std::{coins, from_slice, WasmMsg};
+    use serde::Deserialize;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+
+    fn init_helper() -> Extern<MockStorage, MockApi, MockQuerier> {
+        init_with_address_length(20)
+    }
+
+    /// Permit signers have full length bech32 addresses, longer than the usual mock ones
+    fn init_with_address_length(canonical_length: usize) -> Extern<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies(canonical_length, &[]);
+
+        let init_msg = InitMsg {
+            admin: HumanAddr::from("admin"),
+            operators: vec![HumanAddr::from("operator")],
+            tokens: vec![TokenInfo {
+                address: HumanAddr::from("sscrt"),
+                code_hash: "sscrt_hash".to_string(),
+                denom: "uscrt".to_string(),
+                native: false,
+                fee: FeeSchedule {
+                    flat: Uint128(100_000),
+                    bps: 0,
+                    min: Uint128::zero(),
+                    max: None,
+                    tiers: vec![],
+                    operator_share_bps: 5_000,
+                },
+                denominations: vec![],
+            }],
+            min_delay: 0,
+            min_pool_size: 0,
+            expiry_blocks: 100,
+            entropy: "init entropy".to_string(),
+        };
+        init(&mut deps, mock_env("admin", &[]), init_msg).unwrap();
+
+        deps
+    }
+
+    fn deposit_msg(entropy: Option<String>, commitment: Option<String>) -> HandleMsg {
+        HandleMsg::Receive {
+            sender: HumanAddr::from("alice"),
+            from: HumanAddr::from("alice"),
+            amount: Uint128(1_100_000),
+            msg: Some(to_binary(&HandleReceiveMsg::ReceiveSeed {
+                destinations: vec![Destination { address: HumanAddr::from("wallet"), weight: Uint128(1), commitment }],
+                destination: None,
+                entropy,
+                payout: Payout::Native,
+            }).unwrap()),
+            padding: None,
+        }
+    }
+
+    fn tx_code(response: &HandleResponse) -> Vec<u8> {
+        // Log values are padded with spaces
+        let tx_code = response.log.iter().find(|l| l.key == "tx_code").unwrap();
+        hex::decode(tx_code.value.trim()).unwrap()
+    }
+
+    /// Outgoing snip20 messages carry random padding, so the expected transfer reuses the
+    /// padding that was sent
+    fn expected_transfer(sent: &CosmosMsg, recipient: &str, amount: u128) -> CosmosMsg {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum PaddedMsg {
+            Transfer { padding: Option<String> },
+        }
+
+        let padding = match sent {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => match from_binary(msg).unwrap() {
+                PaddedMsg::Transfer { padding } => padding,
+            },
+            other => panic!("not a snip20 message {:?}", other),
+        };
+        assert!(padding.is_some());
+
+        transfer_msg(HumanAddr::from(recipient), Uint128(amount), padding, BLOCK_SIZE, "sscrt_hash".to_string(), HumanAddr::from("sscrt")).unwrap()
+    }
+
+    #[test]
+    fn test_same_block_deposits_get_unrelated_keys() {
+        let mut deps = init_helper();
+
+        let first = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        let second = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+
+        let first = tx_code(&first);
+        let second = tx_code(&second);
+        assert_ne!(first, second);
+
+        // Independent 256 bit keys differ in about half their bits
+        let differing_bits: u32 = first
+            .iter()
+            .zip(second.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        assert!(differing_bits > 64, "keys share too many bits: {}", differing_bits);
+    }
+
+    #[test]
+    fn test_user_entropy_changes_key() {
+        let mut deps_a = init_helper();
+        let mut deps_b = init_helper();
+
+        let a = handle(&mut deps_a, mock_env("sscrt", &[]), deposit_msg(Some("foo".to_string()), None)).unwrap();
+        let b = handle(&mut deps_b, mock_env("sscrt", &[]), deposit_msg(Some("bar".to_string()), None)).unwrap();
+
+        assert_ne!(tx_code(&a), tx_code(&b));
+    }
+
+    #[test]
+    fn test_commitment_requires_preimage() {
+        let mut deps = init_helper();
+
+        let commitment = hex::encode(sha_256(b"my secret note"));
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, Some(commitment))).unwrap();
+        assert!(response.log.iter().all(|l| l.key != "tx_code"));
+
+        let wrong = HandleMsg::ExitPool { tx_key: "a guessed note".to_string(), padding: None };
+        assert!(handle(&mut deps, mock_env("mallory", &[]), wrong).is_err());
+
+        // The operator is given the secret to finalize, but exiting with it only refunds
+        // the depositor
+        let exit = HandleMsg::ExitPool { tx_key: "my secret note".to_string(), padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), exit).unwrap();
+        let expected = expected_transfer(&response.messages[0], "alice", 1_000_000);
+        assert_eq!(response.messages, vec![expected]);
+
+        // Reusing the commitment would overwrite the closed tx's history
+        let commitment = hex::encode(sha_256(b"my secret note"));
+        assert!(handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, Some(commitment))).is_err());
+    }
+
+    #[test]
+    fn test_migrate_from_first_release() {
+        let mut deps = mock_dependencies(20, &[]);
+
+        let legacy_config = LegacyConfig {
+            admin: deps.api.canonical_address(&HumanAddr::from("admin")).unwrap(),
+            operator: deps.api.canonical_address(&HumanAddr::from("operator")).unwrap(),
+            active: true,
+            fee: Uint128(100_000),
+            op_share: Uint128(50_000),
+        };
+        let legacy_pair = LegacyPair { gas: 1_000_000, address: HumanAddr::from("wallet") };
+
+        save(&mut deps.storage, CONFIG_KEY, &legacy_config).unwrap();
+        save(&mut deps.storage, POOL_SIZE_KEY, &1u16).unwrap();
+        save(&mut deps.storage, PRNG_SEED_KEY, &vec![0u8; 32]).unwrap();
+        save(&mut deps.storage, LEGACY_SNIP20_ADDRESS_KEY, &HumanAddr::from("sscrt")).unwrap();
+        save(&mut deps.storage, LEGACY_SNIP20_HASH_KEY, &"sscrt_hash".to_string()).unwrap();
+        save(&mut deps.storage, b"legacykey", &legacy_pair).unwrap();
+
+        // Every pending key must be listed
+        let missing = MigrateMsg { tx_keys: vec![] };
+        assert!(migrate(&mut deps, mock_env("admin", &[]), missing).is_err());
+
+        let msg = MigrateMsg { tx_keys: vec!["legacykey".to_string()] };
+        migrate(&mut deps, mock_env("admin", &[]), msg).unwrap();
+
+        let pool: PoolSizeResponse = from_binary(&query(&deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+        assert_eq!(pool.pool_size, 1);
+        assert_eq!(pool.tvl, Uint128(1_000_000));
+
+        let info: ContractInfoResponse = from_binary(&query(&deps, QueryMsg::ContractInfo {}).unwrap()).unwrap();
+        assert_eq!(info.storage_version, STORAGE_VERSION);
+        assert_eq!(info.version, CONTRACT_VERSION);
+
+        let exit = HandleMsg::ExitPool { tx_key: "legacykey".to_string(), padding: None };
+        let response = handle(&mut deps, mock_env("alice", &[]), exit).unwrap();
+        assert_eq!(response.messages.len(), 1);
+    }
+
+    fn set_status(deps: &mut Extern<MockStorage, MockApi, MockQuerier>, status: ContractStatus) {
+        let msg = HandleMsg::SetActive { status, padding: None };
+        let response = handle(deps, mock_env("admin", &[]), msg).unwrap();
+        assert!(response.log.iter().any(|l| l.key == "status"));
+    }
+
+    #[test]
+    fn test_set_active_is_admin_only() {
+        let mut deps = init_helper();
+
+        let msg = HandleMsg::SetActive { status: ContractStatus::Paused, padding: None };
+        assert!(handle(&mut deps, mock_env("operator", &[]), msg).is_err());
+
+        set_status(&mut deps, ContractStatus::Paused);
+        set_status(&mut deps, ContractStatus::Active);
+        handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+    }
+
+    #[test]
+    fn test_deposits_paused_still_finalizes() {
+        let mut deps = init_helper();
+
+        let first = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        let second = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+
+        set_status(&mut deps, ContractStatus::DepositsPaused);
+        assert!(handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).is_err());
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(&first))], padding: None };
+        handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+
+        let exit = HandleMsg::ExitPool { tx_key: hex::encode(tx_code(&second)), padding: None };
+        handle(&mut deps, mock_env("alice", &[]), exit).unwrap();
+    }
+
+    #[test]
+    fn test_paused_only_allows_exits() {
+        let mut deps = init_helper();
+
+        let env = mock_env("sscrt", &[]);
+        let deposit_height = env.block.height;
+        let first = handle(&mut deps, env, deposit_msg(None, None)).unwrap();
+        handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+
+        set_status(&mut deps, ContractStatus::Paused);
+        assert!(handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).is_err());
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(&first))], padding: None };
+        assert!(handle(&mut deps, mock_env("operator", &[]), finalize).is_err());
+
+        // Both emergency paths stay open
+        let exit = HandleMsg::ExitPool { tx_key: hex::encode(tx_code(&first)), padding: None };
+        handle(&mut deps, mock_env("alice", &[]), exit).unwrap();
+
+        let reclaim = HandleMsg::ReclaimExpired { depositor: HumanAddr::from("alice"), page: None, page_size: 10, padding: None };
+        let mut env = mock_env("bob", &[]);
+        env.block.height = deposit_height + 100;
+        let response = handle(&mut deps, env, reclaim).unwrap();
+        let expected = expected_transfer(&response.messages[0], "alice", 1_000_000);
+        assert_eq!(response.messages, vec![expected]);
+
+        let pool_size: u64 = load(&deps.storage, POOL_SIZE_KEY).unwrap();
+        assert_eq!(pool_size, 0);
+    }
+
+    #[test]
+    fn test_reclaim_expired_refunds_depositor() {
+        let mut deps = init_helper();
+
+        let env = mock_env("sscrt", &[]);
+        let deposit_height = env.block.height;
+        handle(&mut deps, env, deposit_msg(None, None)).unwrap();
+
+        let reclaim = HandleMsg::ReclaimExpired { depositor: HumanAddr::from("alice"), page: None, page_size: 10, padding: None };
+        let mut env = mock_env("bob", &[]);
+        env.block.height = deposit_height + 99;
+        assert!(handle(&mut deps, env, reclaim.clone()).is_err());
+
+        // Anyone can trigger the refund once expired, but it only pays the depositor
+        let mut env = mock_env("bob", &[]);
+        env.block.height = deposit_height + 100;
+        let response = handle(&mut deps, env, reclaim.clone()).unwrap();
+        let expected = expected_transfer(&response.messages[0], "alice", 1_000_000);
+        assert_eq!(response.messages, vec![expected]);
+
+        let pool_size: u64 = load(&deps.storage, POOL_SIZE_KEY).unwrap();
+        assert_eq!(pool_size, 0);
+
+        let mut env = mock_env("bob", &[]);
+        env.block.height = deposit_height + 100;
+        assert!(handle(&mut deps, env, reclaim).is_err());
+    }
+
+    #[test]
+    fn test_fee_schedule_tiers_and_caps() {
+        let mut schedule = FeeSchedule {
+            flat: Uint128(1_000),
+            bps: 100,
+            min: Uint128(5_000),
+            max: Some(Uint128(50_000)),
+            tiers: vec![FeeTier { min_amount: Uint128(1_000_000), flat: Uint128(0), bps: 50 }],
+            operator_share_bps: 2_500,
+        };
+        assert!(validate_fee_schedule(&schedule).is_ok());
+
+        // 1% of 100_000 plus the flat fee is under the minimum
+        assert_eq!(compute_fee(&schedule, Uint128(100_000)).unwrap(), Uint128(5_000));
+        assert_eq!(compute_fee(&schedule, Uint128(900_000)).unwrap(), Uint128(10_000));
+        // The tier halves the rate and drops the flat fee
+        assert_eq!(compute_fee(&schedule, Uint128(2_000_000)).unwrap(), Uint128(10_000));
+        assert_eq!(compute_fee(&schedule, Uint128(100_000_000)).unwrap(), Uint128(50_000));
+
+        schedule.tiers.push(FeeTier { min_amount: Uint128(1_000_000), flat: Uint128(0), bps: 10 });
+        assert!(validate_fee_schedule(&schedule).is_err());
+    }
+
+    #[test]
+    fn test_fees_accrue_until_withdrawn() {
+        let mut deps = init_helper();
+
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        assert!(response.messages.is_empty());
+        handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+
+        let fees: FeesResponse = from_binary(&query(&deps, QueryMsg::GetFees {}).unwrap()).unwrap();
+        assert_eq!(fees.fees[0].admin_accrued, Uint128(100_000));
+        assert_eq!(fees.fees[0].operator_accrued, Uint128(100_000));
+
+        assert!(handle(&mut deps, mock_env("alice", &[]), HandleMsg::WithdrawFees { padding: None }).is_err());
+
+        let response = handle(&mut deps, mock_env("admin", &[]), HandleMsg::WithdrawFees { padding: None }).unwrap();
+        let expected = expected_transfer(&response.messages[0], "admin", 100_000);
+        assert_eq!(response.messages, vec![expected]);
+        assert!(handle(&mut deps, mock_env("admin", &[]), HandleMsg::WithdrawFees { padding: None }).is_err());
+
+        // Redeem and native send
+        let response = handle(&mut deps, mock_env("operator", &[]), HandleMsg::WithdrawFees { padding: None }).unwrap();
+        assert_eq!(response.messages.len(), 2);
+
+        let fees: FeesResponse = from_binary(&query(&deps, QueryMsg::GetFees {}).unwrap()).unwrap();
+        assert!(fees.fees[0].admin_accrued.is_zero());
+        assert!(fees.fees[0].operator_accrued.is_zero());
+        assert_eq!(fees.fees[0].admin_lifetime, Uint128(100_000));
+    }
+
+    #[test]
+    fn test_pool_tracks_value_locked() {
+        let mut deps = init_helper();
+
+        let first = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+
+        let pool: PoolSizeResponse = from_binary(&query(&deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+        assert_eq!(pool.pool_size, 2);
+        assert_eq!(pool.tvl, Uint128(2_000_000));
+        assert_eq!(pool.tokens, vec![TokenPoolResponse { token: HumanAddr::from("sscrt"), pool_size: 2, amount: Uint128(2_000_000) }]);
 
-    Ok(PoolSizeResponse { pool_size })
+        let exit = HandleMsg::ExitPool { tx_key: hex::encode(tx_code(&first)), padding: None };
+        handle(&mut deps, mock_env("alice", &[]), exit.clone()).unwrap();
+        assert!(handle(&mut deps, mock_env("alice", &[]), exit).is_err());
+
+        let pool: PoolSizeResponse = from_binary(&query(&deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+        assert_eq!(pool.pool_size, 1);
+        assert_eq!(pool.tvl, Uint128(1_000_000));
+    }
+
+    #[test]
+    fn test_native_deposit_pays_out_without_redeem() {
+        let mut deps = init_helper();
+
+        let native = TokenInfo {
+            address: HumanAddr::from("uscrt"),
+            code_hash: String::new(),
+            denom: "uscrt".to_string(),
+            native: true,
+            fee: FeeSchedule {
+                flat: Uint128(100_000),
+                bps: 0,
+                min: Uint128::zero(),
+                max: None,
+                tiers: vec![],
+                operator_share_bps: 5_000,
+            },
+            denominations: vec![],
+        };
+        let response = handle(&mut deps, mock_env("admin", &[]), HandleMsg::AddToken { token: native, padding: None }).unwrap();
+        assert!(response.messages.is_empty());
+
+        let deposit = HandleMsg::DepositNative {
+            destinations: vec![Destination { address: HumanAddr::from("wallet"), weight: Uint128(1), commitment: None }],
+            entropy: None,
+            payout: Payout::Native,
+            padding: None,
+        };
+        assert!(handle(&mut deps, mock_env("alice", &coins(1_100_000, "uatom")), deposit.clone()).is_err());
+        let response = handle(&mut deps, mock_env("alice", &coins(1_100_000, "uscrt")), deposit).unwrap();
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(&response))], padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+        assert_eq!(response.messages, vec![CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("wallet"),
+            amount: coins(1_000_000, "uscrt"),
+        })]);
+    }
+
+    #[test]
+    fn test_snip20_payout_skips_redeem() {
+        let mut deps = init_helper();
+
+        let deposit = HandleMsg::Receive {
+            sender: HumanAddr::from("alice"),
+            from: HumanAddr::from("alice"),
+            amount: Uint128(1_100_000),
+            msg: Some(to_binary(&HandleReceiveMsg::ReceiveSeed {
+                destinations: vec![Destination { address: HumanAddr::from("wallet"), weight: Uint128(1), commitment: None }],
+                destination: None,
+                entropy: None,
+                payout: Payout::Transfer,
+            }).unwrap()),
+            padding: None,
+        };
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit).unwrap();
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(&response))], padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+        let expected = expected_transfer(&response.messages[0], "wallet", 1_000_000);
+        assert_eq!(response.messages, vec![expected]);
+    }
+
+    #[test]
+    fn test_split_deposit_finalizes_independently() {
+        let mut deps = init_helper();
+
+        let deposit = HandleMsg::Receive {
+            sender: HumanAddr::from("alice"),
+            from: HumanAddr::from("alice"),
+            amount: Uint128(1_100_000),
+            msg: Some(to_binary(&HandleReceiveMsg::ReceiveSeed {
+                destinations: vec![
+                    Destination { address: HumanAddr::from("wallet1"), weight: Uint128(1), commitment: None },
+                    Destination { address: HumanAddr::from("wallet2"), weight: Uint128(2), commitment: None },
+                ],
+                destination: None,
+                entropy: None,
+                payout: Payout::Transfer,
+            }).unwrap()),
+            padding: None,
+        };
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit).unwrap();
+
+        let tx_codes: Vec<String> = response.log.iter().filter(|l| l.key == "tx_code").map(|l| l.value.trim().to_string()).collect();
+        assert_eq!(tx_codes.len(), 2);
+        assert_ne!(tx_codes[0], tx_codes[1]);
+
+        let pool: PoolSizeResponse = from_binary(&query(&deps, QueryMsg::GetPoolSize {}).unwrap()).unwrap();
+        assert_eq!(pool.pool_size, 2);
+        assert_eq!(pool.tvl, Uint128(1_000_000));
+
+        // The last destination takes the rounding dust
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_codes[1].clone()], padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+        let expected = expected_transfer(&response.messages[0], "wallet2", 666_667);
+        assert_eq!(response.messages, vec![expected]);
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_codes[0].clone()], padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+        let expected = expected_transfer(&response.messages[0], "wallet1", 333_333);
+        assert_eq!(response.messages, vec![expected]);
+    }
+
+    #[test]
+    fn test_weights_above_u32() {
+        let destinations = vec![
+            Destination { address: HumanAddr::from("wallet1"), weight: Uint128(5_000_000_000), commitment: None },
+            Destination { address: HumanAddr::from("wallet2"), weight: Uint128(10_000_000_000), commitment: None },
+        ];
+        let tokens: Vec<Token> = load(&init_helper().storage, TOKENS_KEY).unwrap();
+
+        let (shares, remainder) = split_gas(&tokens[0], Uint128(1_000_000), &destinations).unwrap();
+        assert_eq!(shares, vec![Uint128(333_333), Uint128(666_667)]);
+        assert!(remainder.is_zero());
+    }
+
+    #[test]
+    fn test_legacy_single_destination() {
+        let mut deps = init_helper();
+
+        let deposit = HandleMsg::Receive {
+            sender: HumanAddr::from("alice"),
+            from: HumanAddr::from("alice"),
+            amount: Uint128(1_100_000),
+            msg: Some(Binary::from(br#"{"receive_seed":{"destination":"wallet"}}"#.to_vec())),
+            padding: None,
+        };
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit).unwrap();
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(&response))], padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(response.messages[1], CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("wallet"),
+            amount: coins(1_000_000, "uscrt"),
+        }));
+    }
+
+    #[test]
+    fn test_deposit_answer() {
+        let mut deps = init_helper();
+
+        // Called directly here. On chain the token sends Receive, so the depositor only gets
+        // the logs, which must carry the same keys
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        let logged_key = hex::encode(tx_code(&response));
+        let answer: HandleAnswer = from_binary(&response.data.unwrap()).unwrap();
+
+        match answer {
+            HandleAnswer::Deposit { status, outputs, net_amount, fee, refunded } => {
+                assert_eq!(status, Success);
+                assert_eq!(net_amount, Uint128(1_000_000));
+                assert_eq!(fee, Uint128(100_000));
+                assert!(refunded.is_zero());
+
+                let tx_key = outputs[0].tx_key.clone().unwrap();
+                assert_eq!(tx_key, logged_key);
+                assert_eq!(outputs[0].id, hex::encode(commitment_key(&tx_key)));
+            }
+            other => panic!("unexpected answer {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_responses_are_padded() {
+        let mut deps = init_helper();
+
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        assert_eq!(response.data.unwrap().as_slice().len() % BLOCK_SIZE, 0);
+        assert!(response.log.iter().all(|l| l.value.len() % BLOCK_SIZE == 0));
+
+        let config = query(&deps, QueryMsg::GetConfig {}).unwrap();
+        assert_eq!(config.as_slice().len() % BLOCK_SIZE, 0);
+    }
+
+    #[test]
+    fn test_errors_carry_codes() {
+        let mut deps = init_helper();
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec!["key".to_string()], padding: None };
+        match handle(&mut deps, mock_env("alice", &[]), finalize) {
+            Err(StdError::GenericErr { msg, .. }) => assert!(msg.starts_with("[not_operator] ")),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let exit = HandleMsg::ExitPool { tx_key: "key".to_string(), padding: None };
+        match handle(&mut deps, mock_env("alice", &[]), exit) {
+            Err(StdError::GenericErr { msg, .. }) => assert!(msg.starts_with("[unknown_key] ")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    /// Address of the key that signed the test permits, which name this contract
+    const PERMIT_SIGNER: &str = "secret1anh8sz0rn6w0e5682t9k7tv8k4h760z7n9vkqx";
+
+    fn permit_query(name: &str, permission: &str, signature: &str, query: &str) -> QueryMsg {
+        let msg = format!(
+            r#"{{"with_permit":{{
+                "permit":{{
+                    "params":{{"permit_name":"{}","allowed_tokens":["{}"],"chain_id":"secret-4","permissions":["{}"]}},
+                    "signature":{{
+                        "pub_key":{{"type":"tendermint/PubKeySecp256k1","value":"A4MlZDmwV0mzE1RXsY/7B7Ffb2DI5z1iDtHXXTgb6sFh"}},
+                        "signature":"{}"
+                    }}
+                }},
+                "query":{}
+            }}}}"#,
+            name, MOCK_CONTRACT_ADDR, permission, signature, query,
+        );
+        from_slice(msg.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_permit_queries_until_revoked() {
+        let mut deps = init_with_address_length(PERMIT_SIGNER.len());
+
+        let deposit = HandleMsg::Receive {
+            sender: HumanAddr::from(PERMIT_SIGNER),
+            from: HumanAddr::from(PERMIT_SIGNER),
+            amount: Uint128(1_100_000),
+            msg: Some(to_binary(&HandleReceiveMsg::ReceiveSeed {
+                destinations: vec![Destination { address: HumanAddr::from("wallet"), weight: Uint128(1), commitment: None }],
+                destination: None,
+                entropy: None,
+                payout: Payout::Native,
+            }).unwrap()),
+            padding: None,
+        };
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit).unwrap();
+        let id = hex::encode(commitment_key(&hex::encode(tx_code(&response))));
+
+        let pending_seeds = r#"{"pending_seeds":{"page_size":10}}"#;
+        let seed_status = format!(r#"{{"seed_status":{{"id":"{}"}}}}"#, id);
+
+        // owner allows every query
+        let owner_signature = "dYhNQn0sExB3M8J4gC6A2SxrD3kXzegoWD+hJ/lKbFAaeL4DIb+Tda1SpjtT0qwMjCC2S8LRDpukZ9fi+Ib+JA==";
+        let query_msg = permit_query("wallet", "owner", owner_signature, pending_seeds);
+        let pending: PendingSeedsResponse = from_binary(&query(&deps, query_msg.clone()).unwrap()).unwrap();
+        assert_eq!(pending.total, 1);
+        assert_eq!(pending.seeds[0].id, id);
+
+        let status: SeedStatusResponse = from_binary(&query(&deps, permit_query("wallet", "owner", owner_signature, &seed_status)).unwrap()).unwrap();
+        assert_eq!(status.status, TxStatus::Pending);
+
+        // balance covers PendingSeeds only
+        let balance_signature = "bDFUnW5LaM0rNJN3U6t+nqt7ZYe/QEg/+PUKlBhwI2kVBUtO0szxrjICKDxsPzRVw4iOW/iNF4HFRVQ0gxRfxQ==";
+        assert!(query(&deps, permit_query("balance_only", "balance", balance_signature, pending_seeds)).is_ok());
+        assert!(query(&deps, permit_query("balance_only", "balance", balance_signature, &seed_status)).is_err());
+
+        // A permit signed for other permissions doesn't verify
+        assert!(query(&deps, permit_query("wallet", "balance", owner_signature, pending_seeds)).is_err());
+
+        let revoke = HandleMsg::RevokePermit { permit_name: "wallet".to_string(), padding: None };
+        handle(&mut deps, mock_env(PERMIT_SIGNER, &[]), revoke).unwrap();
+        assert!(query(&deps, query_msg).is_err());
+        assert!(query(&deps, permit_query("balance_only", "balance", balance_signature, pending_seeds)).is_ok());
+    }
+
+    #[test]
+    fn test_change_operator_settles_fees() {
+        let mut deps = init_helper();
+
+        let first = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        let second = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+
+        let rotate = HandleMsg::ChangeOperator {
+            new_operators: vec![HumanAddr::from("operator2"), HumanAddr::from("operator")],
+            padding: None,
+        };
+        assert!(handle(&mut deps, mock_env("operator", &[]), rotate.clone()).is_err());
+
+        // The outgoing fee receiver gets its share redeemed and sent
+        let response = handle(&mut deps, mock_env("admin", &[]), rotate).unwrap();
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(response.messages[1], CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("operator"),
+            amount: coins(100_000, "uscrt"),
+        }));
+
+        let fees: FeesResponse = from_binary(&query(&deps, QueryMsg::GetFees {}).unwrap()).unwrap();
+        assert!(fees.fees[0].operator_accrued.is_zero());
+        assert_eq!(fees.fees[0].operator_lifetime, Uint128(100_000));
+
+        let admin: AdminResponse = from_binary(&query(&deps, QueryMsg::GetAdmin {}).unwrap()).unwrap();
+        assert_eq!(admin.operators, vec![HumanAddr::from("operator2"), HumanAddr::from("operator")]);
+
+        // Any listed operator can finalize, but only the first receives fees
+        let finalize = |response: &HandleResponse| HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(response))], padding: None };
+        assert!(handle(&mut deps, mock_env("carol", &[]), finalize(&first)).is_err());
+        handle(&mut deps, mock_env("operator2", &[]), finalize(&first)).unwrap();
+        handle(&mut deps, mock_env("operator", &[]), finalize(&second)).unwrap();
+
+        assert!(handle(&mut deps, mock_env("operator", &[]), HandleMsg::WithdrawFees { padding: None }).is_err());
+    }
+
+    fn snip20_info(address: &str, denom: &str) -> TokenInfo {
+        TokenInfo {
+            address: HumanAddr::from(address),
+            code_hash: format!("{}_hash", address),
+            denom: denom.to_string(),
+            native: false,
+            fee: FeeSchedule {
+                flat: Uint128(100_000),
+                bps: 0,
+                min: Uint128::zero(),
+                max: None,
+                tiers: vec![],
+                operator_share_bps: 5_000,
+            },
+            denominations: vec![],
+        }
+    }
+
+    /// Contract and amount of a redeem sent to a snip20
+    fn redeemed(msg: &CosmosMsg) -> Option<(HumanAddr, Uint128)> {
+        match msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => match from_binary(msg) {
+                Ok(RedeemHandleMsg::Redeem { amount, .. }) => Some((contract_addr.clone(), amount)),
+                Err(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn assert_error_code(result: StdResult<HandleResponse>, code: &str) {
+        match result {
+            Err(StdError::GenericErr { msg, .. }) => assert!(msg.starts_with(&format!("[{}] ", code)), "{}", msg),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_receive_requires_accepted_token() {
+        let mut deps = init_helper();
+
+        assert_error_code(handle(&mut deps, mock_env("unknown", &[]), deposit_msg(None, None)), "wrong_token");
+
+        let remove = HandleMsg::RemoveToken { token: HumanAddr::from("sscrt"), padding: None };
+        assert!(handle(&mut deps, mock_env("alice", &[]), remove.clone()).is_err());
+        handle(&mut deps, mock_env("admin", &[]), remove).unwrap();
+
+        assert_error_code(handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)), "wrong_token");
+    }
+
+    #[test]
+    fn test_add_token_readds_removed_token() {
+        let mut deps = init_helper();
+
+        let add = HandleMsg::AddToken { token: snip20_info("sscrt", "uscrt"), padding: None };
+        assert_error_code(handle(&mut deps, mock_env("admin", &[]), add.clone()), "token_already_accepted");
+
+        let remove = HandleMsg::RemoveToken { token: HumanAddr::from("sscrt"), padding: None };
+        handle(&mut deps, mock_env("admin", &[]), remove).unwrap();
+
+        // Re-adding registers the receive hook again
+        let response = handle(&mut deps, mock_env("admin", &[]), add).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        match &response.messages[0] {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. }) => assert_eq!(contract_addr, &HumanAddr::from("sscrt")),
+            other => panic!("not a snip20 message {:?}", other),
+        }
+
+        let response = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(None, None)).unwrap();
+        assert_eq!(tx_code(&response).len(), 32);
+    }
+
+    #[test]
+    fn test_removed_token_still_pays_out() {
+        let mut deps = init_helper();
+
+        let first = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(Some("first".to_string()), None)).unwrap();
+        let second = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(Some("second".to_string()), None)).unwrap();
+
+        let remove = HandleMsg::RemoveToken { token: HumanAddr::from("sscrt"), padding: None };
+        handle(&mut deps, mock_env("admin", &[]), remove).unwrap();
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![hex::encode(tx_code(&first))], padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+        assert_eq!(redeemed(&response.messages[0]), Some((HumanAddr::from("sscrt"), Uint128(1_000_000))));
+        assert_eq!(response.messages[1], CosmosMsg::Bank(BankMsg::Send {
+            from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+            to_address: HumanAddr::from("wallet"),
+            amount: coins(1_000_000, "uscrt"),
+        }));
+
+        let exit = HandleMsg::ExitPool { tx_key: hex::encode(tx_code(&second)), padding: None };
+        let response = handle(&mut deps, mock_env("alice", &[]), exit).unwrap();
+        let expected = expected_transfer(&response.messages[0], "alice", 1_000_000);
+        assert_eq!(response.messages, vec![expected]);
+    }
+
+    #[test]
+    fn test_batch_redeems_once_per_token() {
+        let mut deps = init_helper();
+
+        let add = HandleMsg::AddToken { token: snip20_info("satom", "uatom"), padding: None };
+        handle(&mut deps, mock_env("admin", &[]), add).unwrap();
+
+        let scrt = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(Some("scrt".to_string()), None)).unwrap();
+        let atom = handle(&mut deps, mock_env("satom", &[]), deposit_msg(Some("atom".to_string()), None)).unwrap();
+        let scrt2 = handle(&mut deps, mock_env("sscrt", &[]), deposit_msg(Some("scrt2".to_string()), None)).unwrap();
+
+        let tx_keys = vec![hex::encode(tx_code(&scrt)), hex::encode(tx_code(&atom)), hex::encode(tx_code(&scrt2))];
+        let finalize = HandleMsg::FinalizeSeed { tx_keys, padding: None };
+        let response = handle(&mut deps, mock_env("operator", &[]), finalize).unwrap();
+
+        let redeems: Vec<(HumanAddr, Uint128)> = response.messages.iter().filter_map(redeemed).collect();
+        assert_eq!(redeems, vec![
+            (HumanAddr::from("sscrt"), Uint128(2_000_000)),
+            (HumanAddr::from("satom"), Uint128(1_000_000)),
+        ]);
+        assert_eq!(&response.messages[2..], &[
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from("wallet"),
+                amount: coins(1_000_000, "uscrt"),
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from("wallet"),
+                amount: coins(1_000_000, "uatom"),
+            }),
+            CosmosMsg::Bank(BankMsg::Send {
+                from_address: HumanAddr::from(MOCK_CONTRACT_ADDR),
+                to_address: HumanAddr::from("wallet"),
+                amount: coins(1_000_000, "uscrt"),
+            }),
+        ]);
+    }
+
+    #[test]
+    fn test_reclaim_expired_is_paged() {
+        let mut deps = init_helper();
+
+        let env = mock_env("sscrt", &[]);
+        let deposit_height = env.block.height;
+        for entropy in &["first", "second", "third"] {
+            handle(&mut deps, env.clone(), deposit_msg(Some(entropy.to_string()), None)).unwrap();
+        }
+
+        let reclaim = HandleMsg::ReclaimExpired { depositor: HumanAddr::from("alice"), page: None, page_size: 2, padding: None };
+        let mut env = mock_env("bob", &[]);
+        env.block.height = deposit_height + 100;
+        let response = handle(&mut deps, env.clone(), reclaim.clone()).unwrap();
+        let expected = expected_transfer(&response.messages[0], "alice", 2_000_000);
+        assert_eq!(response.messages, vec![expected]);
+
+        let response = handle(&mut deps, env.clone(), reclaim.clone()).unwrap();
+        let expected = expected_transfer(&response.messages[0], "alice", 1_000_000);
+        assert_eq!(response.messages, vec![expected]);
+
+        assert!(handle(&mut deps, env, reclaim).is_err());
+    }
 }
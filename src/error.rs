@@ -0,0 +1,237 @@
+use cosmwasm_std::{HumanAddr, StdError};
+use secret_toolkit::permit::Permission;
+use snafu::Snafu;
+
+/// Every failure the contract reports itself. Each maps into a `StdError::GenericErr` whose
+/// message starts with the stable code in brackets, e.g. `[not_operator] ...`, so clients can
+/// branch on the code rather than the wording
+#[derive(Snafu, Debug)]
+#[snafu(visibility(pub))]
+pub enum ContractError {
+    #[snafu(display("This function is only usable by the Admin"))]
+    NotAdmin,
+
+    #[snafu(display("This function is only usable by the pending Admin"))]
+    NotPendingAdmin,
+
+    #[snafu(display("This function is only usable by the Operator"))]
+    NotOperator,
+
+    #[snafu(display("This function is only usable by the Admin or the fee receiving Operator"))]
+    NotFeeReceiver,
+
+    #[snafu(display("Wrong viewing key for this address or viewing key not set"))]
+    WrongViewingKey,
+
+    #[snafu(display("No permission to query {}, got permissions {:?}", query, permissions))]
+    NoPermission {
+        query: &'static str,
+        permissions: Vec<Permission>,
+    },
+
+    #[snafu(display("Transfers are currently disabled"))]
+    Paused,
+
+    #[snafu(display("Deposits are currently disabled"))]
+    DepositsDisabled,
+
+    #[snafu(display("Address is not an accepted snip contract"))]
+    WrongToken,
+
+    #[snafu(display("{} is not an accepted native coin", denom))]
+    WrongCoin { denom: String },
+
+    #[snafu(display("Exactly one native coin must be sent"))]
+    WrongFunds,
+
+    #[snafu(display("{} is not a registered token", token))]
+    UnknownToken { token: HumanAddr },
+
+    #[snafu(display("{} is already an accepted token", token))]
+    TokenAlreadyAccepted { token: HumanAddr },
+
+    #[snafu(display("Native coins must be registered with their denom as the address"))]
+    NativeAddressNotDenom,
+
+    #[snafu(display("Native coins can only be paid out natively"))]
+    NativePayoutOnly,
+
+    #[snafu(display("data should be given"))]
+    MissingMsg,
+
+    #[snafu(display("You have not reached the minumum amount for a transaction"))]
+    InsufficientAmount,
+
+    #[snafu(display("The amount after fees does not cover the smallest denomination"))]
+    BelowDenomination,
+
+    #[snafu(display("A deposit must have between 1 and {} destinations", max))]
+    DestinationCount { max: usize },
+
+    #[snafu(display("Destination weights must be positive"))]
+    ZeroWeight,
+
+    #[snafu(display("Every destination must receive a non-zero amount"))]
+    EmptyOutput,
+
+    #[snafu(display("The deposit is too large to split"))]
+    DepositTooLarge,
+
+    #[snafu(display("The commitment must be a hex encoded sha256 hash"))]
+    InvalidCommitment,
+
+    #[snafu(display("This commitment has already been used"))]
+    DuplicateCommitment,
+
+    #[snafu(display("There are no pending transactions with this key."))]
+    UnknownKey,
+
+    #[snafu(display("There are no pending transactions with key #{}", index))]
+    UnknownBatchKey { index: usize },
+
+    #[snafu(display("At least one key must be given"))]
+    NoKeys,
+
+    #[snafu(display("The transaction with key #{} has not been in the pool long enough", index))]
+    TooEarly { index: usize },
+
+    #[snafu(display("Finalizing must leave at least {} transactions of the same token and denomination in the pool", min))]
+    PoolTooSmall { min: u64 },
+
+    #[snafu(display("There are no expired transactions for this depositor"))]
+    NothingExpired,
+
+    #[snafu(display("There is no transaction with this id visible to this address"))]
+    UnknownId,
+
+    #[snafu(display("There are no fees to withdraw"))]
+    NoFees,
+
+    #[snafu(display("At least one operator must be given"))]
+    NoOperators,
+
+    #[snafu(display("Deposits must expire after at least one block"))]
+    InvalidExpiry,
+
+    #[snafu(display("Percentage fees must be below {} basis points", max))]
+    FeeRateTooHigh { max: u16 },
+
+    #[snafu(display("The operator share can be at most {} basis points", max))]
+    OperatorShareTooHigh { max: u16 },
+
+    #[snafu(display("The maximum fee must not be below the minimum fee"))]
+    FeeBoundsInverted,
+
+    #[snafu(display("Fee tiers must be sorted by strictly increasing minimum amount"))]
+    UnsortedFeeTiers,
+
+    #[snafu(display("Denominations must be greater than zero"))]
+    ZeroDenomination,
+
+    #[snafu(display("Denominations must be unique"))]
+    DuplicateDenomination,
+
+    #[snafu(display("The fee overflowed"))]
+    FeeOverflow,
+
+    #[snafu(display("Fee balance overflowed"))]
+    FeeBalanceOverflow,
+
+    #[snafu(display("Pool size is out of range"))]
+    PoolSizeOutOfRange,
+
+    #[snafu(display("Pool value is out of range"))]
+    PoolValueOutOfRange,
+
+    #[snafu(display("The denomination bucket is already empty"))]
+    EmptyBucket,
+
+    #[snafu(display("Cannot migrate from storage version {}", version))]
+    UnsupportedStorageVersion { version: u32 },
+
+    #[snafu(display("Keys are only needed when migrating from the first release"))]
+    UnexpectedKeys,
+
+    #[snafu(display("Expected the keys of all {} pending transactions, got {}", expected, got))]
+    MissingKeys { expected: u64, got: usize },
+}
+
+impl ContractError {
+    /// Stable identifier of the error kind. Codes are never renamed or reused, even when the
+    /// message changes
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContractError::NotAdmin => "not_admin",
+            ContractError::NotPendingAdmin => "not_pending_admin",
+            ContractError::NotOperator => "not_operator",
+            ContractError::NotFeeReceiver => "not_fee_receiver",
+            ContractError::WrongViewingKey => "wrong_viewing_key",
+            ContractError::NoPermission { .. } => "no_permission",
+            ContractError::Paused => "paused",
+            ContractError::DepositsDisabled => "deposits_disabled",
+            ContractError::WrongToken => "wrong_token",
+            ContractError::WrongCoin { .. } => "wrong_coin",
+            ContractError::WrongFunds => "wrong_funds",
+            ContractError::UnknownToken { .. } => "unknown_token",
+            ContractError::TokenAlreadyAccepted { .. } => "token_already_accepted",
+            ContractError::NativeAddressNotDenom => "native_address_not_denom",
+            ContractError::NativePayoutOnly => "native_payout_only",
+            ContractError::MissingMsg => "missing_msg",
+            ContractError::InsufficientAmount => "insufficient_amount",
+            ContractError::BelowDenomination => "below_denomination",
+            ContractError::DestinationCount { .. } => "destination_count",
+            ContractError::ZeroWeight => "zero_weight",
+            ContractError::EmptyOutput => "empty_output",
+            ContractError::DepositTooLarge => "deposit_too_large",
+            ContractError::InvalidCommitment => "invalid_commitment",
+            ContractError::DuplicateCommitment => "duplicate_commitment",
+            ContractError::UnknownKey => "unknown_key",
+            ContractError::UnknownBatchKey { .. } => "unknown_batch_key",
+            ContractError::NoKeys => "no_keys",
+            ContractError::TooEarly { .. } => "too_early",
+            ContractError::PoolTooSmall { .. } => "pool_too_small",
+            ContractError::NothingExpired => "nothing_expired",
+            ContractError::UnknownId => "unknown_id",
+            ContractError::NoFees => "no_fees",
+            ContractError::NoOperators => "no_operators",
+            ContractError::InvalidExpiry => "invalid_expiry",
+            ContractError::FeeRateTooHigh { .. } => "fee_rate_too_high",
+            ContractError::OperatorShareTooHigh { .. } => "operator_share_too_high",
+            ContractError::FeeBoundsInverted => "fee_bounds_inverted",
+            ContractError::UnsortedFeeTiers => "unsorted_fee_tiers",
+            ContractError::ZeroDenomination => "zero_denomination",
+            ContractError::DuplicateDenomination => "duplicate_denomination",
+            ContractError::FeeOverflow => "fee_overflow",
+            ContractError::FeeBalanceOverflow => "fee_balance_overflow",
+            ContractError::PoolSizeOutOfRange => "pool_size_out_of_range",
+            ContractError::PoolValueOutOfRange => "pool_value_out_of_range",
+            ContractError::EmptyBucket => "empty_bucket",
+            ContractError::UnsupportedStorageVersion { .. } => "unsupported_storage_version",
+            ContractError::UnexpectedKeys => "unexpected_keys",
+            ContractError::MissingKeys { .. } => "missing_keys",
+        }
+    }
+}
+
+impl From<ContractError> for StdError {
+    fn from(err: ContractError) -> Self {
+        StdError::generic_err(format!("[{}] {}", err.code(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_carries_code() {
+        let err: StdError = ContractError::TooEarly { index: 2 }.into();
+
+        match err {
+            StdError::GenericErr { msg, .. } => {
+                assert_eq!(msg, "[too_early] The transaction with key #2 has not been in the pool long enough");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
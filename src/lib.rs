@@ -1,14 +1,19 @@
 pub mod contract;
+pub mod error;
 pub mod msg;
 pub mod state;
 pub mod rand;
+pub mod viewing_key;
+
+#[cfg(test)]
+mod testing;
 
 #[cfg(target_arch = "wasm32")]
 mod wasm {
     use super::contract;
-    use cosmwasm_std::{
-        do_handle, do_init, do_query, ExternalApi, ExternalQuerier, ExternalStorage,
-    };
+    use cosmwasm_std::{do_handle, do_init, do_query, ExternalApi, ExternalQuerier, ExternalStorage};
+    #[cfg(feature = "migrate")]
+    use cosmwasm_std::do_migrate;
 
     #[no_mangle]
     extern "C" fn init(env_ptr: u32, msg_ptr: u32) -> u32 {
@@ -28,6 +33,16 @@ mod wasm {
         )
     }
 
+    #[cfg(feature = "migrate")]
+    #[no_mangle]
+    extern "C" fn migrate(env_ptr: u32, msg_ptr: u32) -> u32 {
+        do_migrate(
+            &contract::migrate::<ExternalStorage, ExternalApi, ExternalQuerier>,
+            env_ptr,
+            msg_ptr,
+        )
+    }
+
     #[no_mangle]
     extern "C" fn query(msg_ptr: u32) -> u32 {
         do_query(
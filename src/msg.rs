@@ -2,8 +2,12 @@ use cosmwasm_std::{Binary, HumanAddr, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use secret_toolkit::permit::Permit;
 use secret_toolkit::utils::{HandleCallback};
 
+use crate::state::{ContractStatus, FeeSchedule, Payout, TxStatus};
+use crate::viewing_key::ViewingKey;
+
 
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -11,15 +15,19 @@ pub struct InitMsg {
     /// Recipient of fees and able to make adjustments
     pub admin: HumanAddr,
 
-    pub operator: HumanAddr,
-
-    /// Cost of every use
-    pub fee: Uint128,
-    pub op_share: Uint128,
+    /// Able to finalize txs. The first one receives the operator fee
+    pub operators: Vec<HumanAddr>,
 
+    /// Snip20 tokens accepted from the start
+    pub tokens: Vec<TokenInfo>,
 
-    pub sscrt_addr: HumanAddr,
-    pub sscrt_hash: String,
+    /// Seconds a deposit must wait before it can be finalized
+    pub min_delay: u64,
+    /// Pending txs of the same token, or the same denomination for bucketed tokens, that
+    /// must remain after a batch is finalized. The last ones leave by exit or reclaim
+    pub min_pool_size: u64,
+    /// Blocks after which an unfinalized deposit can be refunded to its depositor
+    pub expiry_blocks: u64,
 
 
     pub entropy: String,
@@ -27,15 +35,72 @@ pub struct InitMsg {
 }
 
 
+/// Registration details of an accepted snip20 or native coin
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenInfo {
+    /// For native coins this must be the denom
+    pub address: HumanAddr,
+    pub code_hash: String,
+    /// Native denom the token redeems into
+    pub denom: String,
+    /// Accept the native coin itself, sent with DepositNative
+    #[serde(default)]
+    pub native: bool,
+
+    /// Cost of every use
+    pub fee: FeeSchedule,
+
+    /// Fixed deposit sizes. Leave empty to accept any amount
+    #[serde(default)]
+    pub denominations: Vec<Uint128>,
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {
+    /// Keys of every pending tx, as published in tx_code logs. Only needed when
+    /// migrating from the first release, which could not enumerate them
+    #[serde(default)]
+    pub tx_keys: Vec<String>,
+}
+
+
 #[derive(Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum HandleReceiveMsg {
     ReceiveSeed {
-        destination: HumanAddr
+        /// Wallets the deposit is split between, each finalized on its own
+        #[serde(default)]
+        destinations: Vec<Destination>,
+        /// Single wallet, as sent by clients of the first release. Taken as one more
+        /// destination with a weight of 1
+        #[serde(default)]
+        destination: Option<HumanAddr>,
+        /// Optional extra randomness mixed into the exit keys
+        #[serde(default)]
+        entropy: Option<String>,
+        /// How the destinations are paid. Defaults to the native denom
+        #[serde(default = "Payout::native")]
+        payout: Payout,
      },
 }
 
 
+/// One output of a deposit
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Destination {
+    pub address: HumanAddr,
+    /// Share of the deposit after fees, relative to the other destinations. The wanted
+    /// amounts themselves work as weights
+    pub weight: Uint128,
+    /// Hex encoded sha256 of a secret only the depositor knows. When given, no exit
+    /// key is published for this output and the secret must be presented to finalize or exit.
+    /// Exits of this output always refund the depositor
+    #[serde(default)]
+    pub commitment: Option<String>,
+}
+
+
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
@@ -55,58 +120,367 @@ impl HandleCallback for RedeemHandleMsg {
 
 
 
+/// Every message takes an optional padding, which is ignored, so senders can make all their
+/// messages the same size
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum HandleMsg {
-    /// Receive Snip20 Payment
+    /// Receive Snip20 Payment. The depositor gets the tx_code logs, not the HandleAnswer
     Receive {
         sender: HumanAddr,
         from: HumanAddr,
         amount: Uint128,
         #[serde(default)]
         msg: Option<Binary>,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Deposit the native coin sent with the message. Works like a snip20 ReceiveSeed
+    DepositNative {
+        destinations: Vec<Destination>,
+        #[serde(default)]
+        entropy: Option<String>,
+        #[serde(default = "Payout::native")]
+        payout: Payout,
+        #[serde(default)]
+        padding: Option<String>,
     },
+    /// Operator only. Pays out every pending seed in the batch. Each key is either an
+    /// exit key or the secret behind a commitment
     FinalizeSeed {
-        tx_key: String,
+        tx_keys: Vec<String>,
+        #[serde(default)]
+        padding: Option<String>,
     },
+    /// Refunds a pending seed to whoever presents its exit key. Seeds deposited under a
+    /// commitment are refunded to their depositor, whoever presents the secret
     ExitPool {
-        tx_key: String
+        tx_key: String,
+        #[serde(default)]
+        padding: Option<String>,
     },
+    /// Refunds the expired pending seeds in a page of the depositor's deposits back to
+    /// them. Anyone can send this, since the funds only ever go to the original depositor
+    ReclaimExpired {
+        depositor: HumanAddr,
+        page: Option<u32>,
+        page_size: u32,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Pays out the fees accrued for the sender, the admin or the first operator. The
+    /// operator share is redeemed and sent in the native denom
+    WithdrawFees {
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Replace the fee schedule of a token
     ChangeFee {
-        new_fee: Uint128,
-        new_op_share: Uint128,
+        token: HumanAddr,
+        fee: FeeSchedule,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Start accepting a snip20, or update one that was removed
+    AddToken {
+        token: TokenInfo,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Stop accepting deposits in a snip20. Pending txs can still be paid out
+    RemoveToken {
+        token: HumanAddr,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Replace the fixed deposit sizes of a token. Empty accepts any amount
+    SetDenominations {
+        token: HumanAddr,
+        denominations: Vec<Uint128>,
+        #[serde(default)]
+        padding: Option<String>,
     },
+    /// Propose a new admin. Nothing changes until they send AcceptAdmin
     ChangeAdmin {
         new_admin: HumanAddr,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Sent by the proposed admin to complete the handover
+    AcceptAdmin {
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Replace the set of operators. Any of them can finalize. The first receives the
+    /// operator fees, so if it changes, the previous one is paid what it accrued
+    ChangeOperator {
+        new_operators: Vec<HumanAddr>,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Generate a viewing key for the sender from the given entropy
+    CreateViewingKey {
+        entropy: String,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Set the sender's viewing key
+    SetViewingKey {
+        key: String,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Stop accepting the sender's permit with this name
+    RevokePermit {
+        permit_name: String,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Pause deposits, pause everything, or resume. Exits always stay open
+    SetActive {
+        status: ContractStatus,
+        #[serde(default)]
+        padding: Option<String>,
+    },
+    /// Set the delay and anonymity threshold finalization waits on, and how long
+    /// deposits wait before they can be reclaimed. Expiry of existing deposits is unchanged
+    ChangeReleaseConditions {
+        min_delay: u64,
+        min_pool_size: u64,
+        expiry_blocks: u64,
+        #[serde(default)]
+        padding: Option<String>,
     },
 }
 
+/// Typed result of every handle message, returned in the response data.
+///
+/// The chain only returns data to the sender of the outer message. A snip20 deposit reaches
+/// the pool as a Receive sent by the token, so its Deposit answer is dropped and the
+/// depositor only sees the logs: one `tx_code` per generated key, in destination order.
+/// Outputs can then be looked up with PendingSeeds. DepositNative callers get the answer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HandleAnswer {
+    /// Answer to DepositNative. Also built for Receive, where only the token sees it
+    Deposit {
+        status: ResponseStatus,
+        /// One per destination, in the order given
+        outputs: Vec<DepositOutput>,
+        /// Total put into the pool after the fee
+        net_amount: Uint128,
+        /// Charged on the net amount only, never on what is refunded
+        fee: Uint128,
+        /// Sent back for not fitting the token's denominations
+        refunded: Uint128,
+    },
+    Finalize {
+        status: ResponseStatus,
+        finalized: u32,
+    },
+    Exit {
+        status: ResponseStatus,
+        refunded: Uint128,
+    },
+    ReclaimExpired {
+        status: ResponseStatus,
+        reclaimed: u32,
+    },
+    WithdrawFees { status: ResponseStatus },
+    ChangeFee { status: ResponseStatus },
+    AddToken { status: ResponseStatus },
+    RemoveToken { status: ResponseStatus },
+    SetDenominations { status: ResponseStatus },
+    ChangeAdmin { status: ResponseStatus },
+    AcceptAdmin { status: ResponseStatus },
+    ChangeOperator { status: ResponseStatus },
+    CreateViewingKey {
+        status: ResponseStatus,
+        key: ViewingKey,
+    },
+    SetViewingKey { status: ResponseStatus },
+    RevokePermit { status: ResponseStatus },
+    SetActive { status: ResponseStatus },
+    ChangeReleaseConditions { status: ResponseStatus },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseStatus {
+    Success,
+    Failure,
+}
+
+/// A pending tx created by a deposit
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DepositOutput {
+    /// Hex encoded commitment, the id used by PendingSeeds and SeedStatus
+    pub id: String,
+    /// Exit key, unless the destination gave its own commitment
+    pub tx_key: Option<String>,
+    pub amount: Uint128,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
     // GetCount returns the current count as a json-encoded number
     GetConfig {},
-    GetExists {
-        tx_key: String
+    GetPoolSize {},
+    GetAdmin {},
+    /// Fees accrued in each token
+    GetFees {},
+    /// Name and version of the deployed code
+    ContractInfo {},
+    /// Pending txs visible to the address. Operators see the whole pool, depositors
+    /// their own deposits
+    PendingSeeds {
+        address: HumanAddr,
+        key: String,
+        page: Option<u32>,
+        page_size: u32,
+    },
+    /// Status of a tx, only answered for its depositor or an operator
+    SeedStatus {
+        address: HumanAddr,
+        key: String,
+        /// Hex encoded commitment of the tx, as listed by PendingSeeds. For contract
+        /// generated keys this is the sha256 of the tx_code
+        id: String,
+    },
+    /// Authenticated queries using a SNIP-24 permit instead of a viewing key
+    WithPermit {
+        permit: Permit,
+        query: QueryWithPermit,
+    }
+}
+
+/// Queries answered for the signer of a permit. The toolkit's permits only carry the
+/// SNIP-24 permission names, so each query is mapped to the closest one: `balance`
+/// allows PendingSeeds, the funds the signer has in the pool, `history` allows
+/// SeedStatus, the lifecycle of one of their txs, and `owner` allows both
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryWithPermit {
+    PendingSeeds {
+        page: Option<u32>,
+        page_size: u32,
     },
-    GetPoolSize {}
+    SeedStatus {
+        id: String,
+    }
 }
 
 // We define a custom struct for each query response
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ConfigResponse {
-    pub active: bool,
-    pub fee: Uint128
+    pub status: ContractStatus,
+    pub tokens: Vec<TokenResponse>,
+    pub min_delay: u64,
+    pub min_pool_size: u64,
+    pub expiry_blocks: u64
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenResponse {
+    pub address: HumanAddr,
+    pub denom: String,
+    pub native: bool,
+    /// Enough to quote the fee on any deposit before making it
+    pub fee: FeeSchedule,
+    pub denominations: Vec<Uint128>
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AdminResponse {
+    pub admin: HumanAddr,
+    pub pending_admin: Option<HumanAddr>,
+    pub operators: Vec<HumanAddr>
 }
 
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct ExistsResponse {
-    pub exists: bool
+pub struct FeesResponse {
+    pub fees: Vec<FeeBalanceResponse>
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeeBalanceResponse {
+    pub token: HumanAddr,
+    /// Waiting to be withdrawn
+    pub admin_accrued: Uint128,
+    pub operator_accrued: Uint128,
+    /// Accrued since deployment, withdrawn or not
+    pub admin_lifetime: Uint128,
+    pub operator_lifetime: Uint128
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ContractInfoResponse {
+    pub contract: String,
+    pub version: String,
+    pub storage_version: u32
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SeedStatusResponse {
+    pub status: TxStatus,
+    pub deposit_height: u64,
+    pub deposit_time: u64,
+    pub closed_height: Option<u64>,
+    pub closed_time: Option<u64>
 }
 
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct PoolSizeResponse {
-    pub pool_size: u16
-}
\ No newline at end of file
+    pub pool_size: u64,
+    /// Total gas of every pending tx, summed across tokens
+    pub tvl: Uint128,
+    pub tokens: Vec<TokenPoolResponse>,
+    pub buckets: Vec<BucketResponse>
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenPoolResponse {
+    pub token: HumanAddr,
+    pub pool_size: u64,
+    pub amount: Uint128
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BucketResponse {
+    pub token: HumanAddr,
+    pub amount: Uint128,
+    pub pool_size: u64
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingSeedsResponse {
+    pub seeds: Vec<PendingSeed>,
+    /// Number of pending txs visible across all pages
+    pub total: u64
+}
+
+
+/// A pending tx. Age is measured from the deposit height and time
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingSeed {
+    /// Hex encoded commitment the tx is stored under
+    pub id: String,
+    pub token: HumanAddr,
+    pub amount: Uint128,
+    pub destination: HumanAddr,
+    pub height: u64,
+    pub time: u64,
+    /// Block height from which the tx can be reclaimed by its depositor
+    pub expiry_height: u64
+}
@@ -7,43 +7,262 @@ use secret_toolkit::{
 };
 
 
-use cosmwasm_std::{CanonicalAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128, HumanAddr};
-//use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
+use cosmwasm_std::{Binary, CanonicalAddr, ReadonlyStorage, StdError, StdResult, Storage, Uint128, HumanAddr};
+use cosmwasm_storage::{PrefixedStorage, ReadonlyPrefixedStorage};
+
+use crate::viewing_key::ViewingKey;
 
 pub static CONFIG_KEY: &[u8] = b"config";
 pub static POOL_SIZE_KEY: &[u8] = b"poolsize";
+/// Storage for the total gas of every pending tx
+pub static TVL_KEY: &[u8] = b"tvl";
+/// Storage for the pool size and total gas of each token
+pub static TOKEN_POOLS_KEY: &[u8] = b"tokenpools";
 
 
 pub static PRNG_SEED_KEY: &[u8] = b"prng";
 
-/// prefix for the storage of snip20 address
-pub const SNIP20_ADDRESS_KEY: &[u8] = b"sscrt";
-/// Storage for storing the hash of the snip20 contract
-pub const SNIP20_HASH_KEY: &[u8] = b"callback";
+/// Storage for the name and version of the code that last ran init or migrate
+pub static CONTRACT_VERSION_KEY: &[u8] = b"contractversion";
+/// Storage for the version of the storage layout below
+pub static STORAGE_VERSION_KEY: &[u8] = b"storageversion";
+/// Current storage layout. Bump whenever a stored type or key changes
+pub const STORAGE_VERSION: u32 = 1;
+
+/// Storage for the registry of accepted snip20 tokens
+pub static TOKENS_KEY: &[u8] = b"tokens";
+/// Storage for the pool size of every denomination bucket
+pub static BUCKETS_KEY: &[u8] = b"buckets";
+/// Storage for the fees accrued in each token
+pub static FEES_KEY: &[u8] = b"fees";
+
+/// prefix for the storage of pending txs
+pub static PREFIX_PAIRS: &[u8] = b"pairs";
+/// prefix for the storage of hashed viewing keys
+pub static PREFIX_VIEW_KEY: &[u8] = b"viewingkey";
+/// prefix for the storage of the commitments of every pending tx
+pub static PREFIX_PENDING: &[u8] = b"pending";
+/// prefix for the storage of each depositor's pending commitments
+pub static PREFIX_DEPOSITS: &[u8] = b"deposits";
+/// prefix for the storage of the status history of every tx
+pub static PREFIX_RECORDS: &[u8] = b"records";
+/// prefix for the storage of revoked permits
+pub const PREFIX_REVOKED_PERMITS: &str = "revoked_permits";
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Config {
     // Permissions to edit rates
     pub admin: CanonicalAddr,
-    // Permission to send out txs
-    pub operator: CanonicalAddr,
-    // Marks whether txs are allowed to be sent
-    pub active: bool,
+    // Proposed admin, who must accept before taking over
+    pub pending_admin: Option<CanonicalAddr>,
+    // Permission to send out txs. The first operator receives the operator fee
+    pub operators: Vec<CanonicalAddr>,
+    // Marks which txs are allowed to be sent
+    pub status: ContractStatus,
 
+    // Seconds a deposit must sit in the pool before it can be finalized
+    pub min_delay: u64,
+    // Pending txs that must remain in the pool of each token, or each denomination bucket,
+    // after a batch is finalized
+    pub min_pool_size: u64,
+    // Blocks after a deposit when anyone can refund it to its depositor
+    pub expiry_blocks: u64,
 
+    // Address of this contract, which permits must name since queries have no env
+    pub contract_address: HumanAddr,
+}
 
-    // Minimum amount of funds that can be sent through the contract
-    pub fee: Uint128,
-    pub op_share: Uint128,
+/// How much of the contract is running. Exits are allowed in every status so depositors
+/// can always reclaim their funds
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ContractStatus {
+    /// Deposits and payouts are both allowed
+    Active,
+    /// New deposits are refused but pending txs can still be finalized
+    DepositsPaused,
+    /// Deposits and payouts are refused
+    Paused,
+}
+
+/// A snip20 token or native coin the pool accepts deposits in
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Token {
+    /// Address of the snip20 contract. Native coins are registered under their denom
+    pub address: HumanAddr,
+    /// Code hash of the snip20 contract. Empty for native coins
+    pub code_hash: String,
+    /// Native denom the token redeems into
+    pub denom: String,
+    /// Whether deposits are native coins, held and paid out without a snip20
+    pub native: bool,
 
+    /// Fee charged on every deposit and how it is split with the operator
+    pub fee: FeeSchedule,
 
+    /// Fixed amounts deposits are rounded down to. Empty allows any amount
+    pub denominations: Vec<Uint128>,
+
+    /// Whether new deposits are accepted. Removed tokens stay listed so pending
+    /// txs can still be paid out
+    pub accepted: bool,
+}
+
+/// How the fee on a deposit is computed. The base rate applies below the first tier
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeeSchedule {
+    /// Charged on every deposit
+    pub flat: Uint128,
+    /// Charged on top of the flat fee, in basis points of the deposit
+    #[serde(default)]
+    pub bps: u16,
+    /// Bounds applied to the total fee
+    #[serde(default)]
+    pub min: Uint128,
+    #[serde(default)]
+    pub max: Option<Uint128>,
+    /// Rates for larger deposits, sorted by increasing min_amount
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    /// Part of the fee paid to the operator, in basis points. The admin gets the rest
+    pub operator_share_bps: u16,
+}
+
+/// Rate replacing the base rate of a schedule for deposits of at least min_amount
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeeTier {
+    pub min_amount: Uint128,
+    pub flat: Uint128,
+    pub bps: u16,
 }
 
 /// Pair of the recipient address and the gas amount they are sending
 #[derive(Serialize, Deserialize, Clone, JsonSchema, PartialEq, Debug)]
 pub struct  Pair {
     pub gas: u128,
-    pub address: HumanAddr
+    pub address: HumanAddr,
+    /// Registry key of the token the gas is held in, a snip20 address or native denom
+    pub token: HumanAddr,
+    /// Block height of the deposit
+    pub height: u64,
+    /// Block time of the deposit, in seconds
+    pub time: u64,
+    /// Whether the gas is one of the token's denominations and counted in its bucket
+    pub bucketed: bool,
+    /// Address the deposit came from
+    pub depositor: CanonicalAddr,
+    /// Block height from which the deposit can be refunded to its depositor
+    pub expiry_height: u64,
+    /// How the gas reaches the destination on finalize
+    pub payout: Payout,
+    /// Whether the depositor supplied the commitment. The operator learns its secret to
+    /// finalize, so exits of these refund the depositor instead of paying the sender
+    pub committed: bool,
+}
+
+/// How a finalized tx is paid to its destination
+#[derive(Serialize, Deserialize, Clone, JsonSchema, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Payout {
+    /// Redeemed and sent in the native denom. The only option for native coins
+    Native,
+    /// Transferred in the snip20 itself
+    Transfer,
+    /// Sent in the snip20 with a msg for the receiving contract's hook
+    Send {
+        msg: Option<Binary>,
+    },
+}
+
+impl Payout {
+    /// Used when a deposit names no payout
+    pub fn native() -> Self {
+        Payout::Native
+    }
+}
+
+/// Where a tx is in its lifecycle
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    Pending,
+    Finalized,
+    Exited,
+    /// Refunded to the depositor after expiring
+    Reclaimed,
+}
+
+/// Status history of a tx, kept after it leaves the pool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TxRecord {
+    pub depositor: CanonicalAddr,
+    pub status: TxStatus,
+    /// Block height and time of the deposit
+    pub height: u64,
+    pub time: u64,
+    /// Block height and time the tx was finalized, exited or reclaimed
+    pub closed_height: Option<u64>,
+    pub closed_time: Option<u64>,
+}
+
+/// Number of pending txs holding one fixed denomination of a token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Bucket {
+    pub token: HumanAddr,
+    pub amount: Uint128,
+    pub pool_size: u64,
+}
+
+/// Number and total gas of the pending txs held in one token
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TokenPool {
+    pub token: HumanAddr,
+    pub pool_size: u64,
+    pub amount: Uint128,
+}
+
+
+/// Fees accrued in one token, held by the contract until withdrawn
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeeBalance {
+    pub token: HumanAddr,
+    /// Owed to the admin and to the fee receiving operator
+    pub admin: Uint128,
+    pub operator: Uint128,
+    /// Everything ever accrued, including what has been withdrawn
+    pub admin_lifetime: Uint128,
+    pub operator_lifetime: Uint128,
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ContractVersion {
+    pub contract: String,
+    pub version: String,
+}
+
+
+// Layout of the first release, which had no storage version. Only read by migrate
+
+/// Storage of the single snip20 address
+pub static LEGACY_SNIP20_ADDRESS_KEY: &[u8] = b"sscrt";
+/// Storage of the single snip20 code hash
+pub static LEGACY_SNIP20_HASH_KEY: &[u8] = b"callback";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LegacyConfig {
+    pub admin: CanonicalAddr,
+    pub operator: CanonicalAddr,
+    pub active: bool,
+    pub fee: Uint128,
+    pub op_share: Uint128,
+}
+
+/// Stored at the root of storage, keyed by the tx_key string
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LegacyPair {
+    pub gas: u128,
+    pub address: HumanAddr,
 }
 
 
@@ -104,3 +323,303 @@ pub fn save<T: Serialize, S: Storage>(storage: &mut S, key: &[u8], value: &T) ->
 pub fn remove<S: Storage>(storage: &mut S, key: &[u8]) {
     storage.remove(key);
 }
+
+
+
+
+
+/// Pending txs, keyed by the commitment they were deposited under
+pub struct PairStore<'a, S: Storage> {
+    storage: PrefixedStorage<'a, S>,
+}
+
+impl<'a, S: Storage> PairStore<'a, S> {
+    pub fn from_storage(storage: &'a mut S) -> Self {
+        Self {
+            storage: PrefixedStorage::new(PREFIX_PAIRS, storage),
+        }
+    }
+
+    pub fn may_load(&self, commitment: &[u8]) -> StdResult<Option<Pair>> {
+        may_load(&self.storage, commitment)
+    }
+
+    pub fn save(&mut self, commitment: &[u8], pair: &Pair) -> StdResult<()> {
+        save(&mut self.storage, commitment, pair)
+    }
+
+    pub fn remove(&mut self, commitment: &[u8]) {
+        remove(&mut self.storage, commitment)
+    }
+}
+
+pub struct ReadonlyPairStore<'a, S: ReadonlyStorage> {
+    storage: ReadonlyPrefixedStorage<'a, S>,
+}
+
+impl<'a, S: ReadonlyStorage> ReadonlyPairStore<'a, S> {
+    pub fn from_storage(storage: &'a S) -> Self {
+        Self {
+            storage: ReadonlyPrefixedStorage::new(PREFIX_PAIRS, storage),
+        }
+    }
+
+    pub fn load(&self, commitment: &[u8]) -> StdResult<Pair> {
+        load(&self.storage, commitment)
+    }
+
+    pub fn may_load(&self, commitment: &[u8]) -> StdResult<Option<Pair>> {
+        may_load(&self.storage, commitment)
+    }
+}
+
+
+
+/// Status history of every tx, keyed by commitment
+pub struct RecordStore<'a, S: Storage> {
+    storage: PrefixedStorage<'a, S>,
+}
+
+impl<'a, S: Storage> RecordStore<'a, S> {
+    pub fn from_storage(storage: &'a mut S) -> Self {
+        Self {
+            storage: PrefixedStorage::new(PREFIX_RECORDS, storage),
+        }
+    }
+
+    pub fn may_load(&self, commitment: &[u8]) -> StdResult<Option<TxRecord>> {
+        may_load(&self.storage, commitment)
+    }
+
+    pub fn save(&mut self, commitment: &[u8], record: &TxRecord) -> StdResult<()> {
+        save(&mut self.storage, commitment, record)
+    }
+}
+
+pub struct ReadonlyRecordStore<'a, S: ReadonlyStorage> {
+    storage: ReadonlyPrefixedStorage<'a, S>,
+}
+
+impl<'a, S: ReadonlyStorage> ReadonlyRecordStore<'a, S> {
+    pub fn from_storage(storage: &'a S) -> Self {
+        Self {
+            storage: ReadonlyPrefixedStorage::new(PREFIX_RECORDS, storage),
+        }
+    }
+
+    pub fn may_load(&self, commitment: &[u8]) -> StdResult<Option<TxRecord>> {
+        may_load(&self.storage, commitment)
+    }
+}
+
+
+
+/// Hashed viewing keys, keyed by owner
+pub struct ViewingKeyStore<'a, S: Storage> {
+    storage: PrefixedStorage<'a, S>,
+}
+
+impl<'a, S: Storage> ViewingKeyStore<'a, S> {
+    pub fn from_storage(storage: &'a mut S) -> Self {
+        Self {
+            storage: PrefixedStorage::new(PREFIX_VIEW_KEY, storage),
+        }
+    }
+
+    pub fn save(&mut self, owner: &CanonicalAddr, key: &ViewingKey) {
+        self.storage.set(owner.as_slice(), &key.to_hashed());
+    }
+}
+
+pub struct ReadonlyViewingKeyStore<'a, S: ReadonlyStorage> {
+    storage: ReadonlyPrefixedStorage<'a, S>,
+}
+
+impl<'a, S: ReadonlyStorage> ReadonlyViewingKeyStore<'a, S> {
+    pub fn from_storage(storage: &'a S) -> Self {
+        Self {
+            storage: ReadonlyPrefixedStorage::new(PREFIX_VIEW_KEY, storage),
+        }
+    }
+
+    /// Returns the hashed viewing key of an address, if one was set
+    pub fn load(&self, owner: &CanonicalAddr) -> Option<Vec<u8>> {
+        self.storage.get(owner.as_slice())
+    }
+}
+
+
+
+/// Keys of a commitment list. Items are stored by position and positions by commitment
+const LIST_LEN_KEY: &[u8] = b"len";
+const LIST_ITEM_PREFIX: u8 = b'i';
+const LIST_INDEX_PREFIX: u8 = b'x';
+
+fn list_item_key(index: u64) -> Vec<u8> {
+    let mut key = vec![LIST_ITEM_PREFIX];
+    key.extend_from_slice(&index.to_be_bytes());
+    key
+}
+
+fn list_index_key(commitment: &[u8; 32]) -> Vec<u8> {
+    let mut key = vec![LIST_INDEX_PREFIX];
+    key.extend_from_slice(commitment);
+    key
+}
+
+fn list_len<S: ReadonlyStorage>(storage: &S) -> StdResult<u64> {
+    Ok(may_load(storage, LIST_LEN_KEY)?.unwrap_or(0))
+}
+
+/// Commitments of pending txs, either the whole pool or one depositor's. Adding and
+/// removing touch a fixed number of entries however long the list is. Removal moves the
+/// last commitment into the freed position, so the list is in no particular order
+pub struct CommitmentList<'a, S: Storage> {
+    storage: PrefixedStorage<'a, S>,
+}
+
+impl<'a, S: Storage> CommitmentList<'a, S> {
+    pub fn pending(storage: &'a mut S) -> Self {
+        Self {
+            storage: PrefixedStorage::new(PREFIX_PENDING, storage),
+        }
+    }
+
+    pub fn deposits(storage: &'a mut S, depositor: &CanonicalAddr) -> Self {
+        Self {
+            storage: PrefixedStorage::multilevel(&[PREFIX_DEPOSITS, depositor.as_slice()], storage),
+        }
+    }
+
+    pub fn push(&mut self, commitment: &[u8; 32]) -> StdResult<()> {
+        let len = list_len(&self.storage)?;
+
+        save(&mut self.storage, &list_item_key(len), commitment)?;
+        save(&mut self.storage, &list_index_key(commitment), &len)?;
+        save(&mut self.storage, LIST_LEN_KEY, &(len + 1))
+    }
+
+    /// Removes a commitment if it is listed
+    pub fn remove(&mut self, commitment: &[u8; 32]) -> StdResult<()> {
+        let index: u64 = match may_load(&self.storage, &list_index_key(commitment))? {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let last = list_len(&self.storage)? - 1;
+
+        if index != last {
+            let moved: [u8; 32] = load(&self.storage, &list_item_key(last))?;
+            save(&mut self.storage, &list_item_key(index), &moved)?;
+            save(&mut self.storage, &list_index_key(&moved), &index)?;
+        }
+
+        remove(&mut self.storage, &list_item_key(last));
+        remove(&mut self.storage, &list_index_key(commitment));
+        save(&mut self.storage, LIST_LEN_KEY, &last)
+    }
+}
+
+pub struct ReadonlyCommitmentList<'a, S: ReadonlyStorage> {
+    storage: ReadonlyPrefixedStorage<'a, S>,
+}
+
+impl<'a, S: ReadonlyStorage> ReadonlyCommitmentList<'a, S> {
+    pub fn pending(storage: &'a S) -> Self {
+        Self {
+            storage: ReadonlyPrefixedStorage::new(PREFIX_PENDING, storage),
+        }
+    }
+
+    pub fn deposits(storage: &'a S, depositor: &CanonicalAddr) -> Self {
+        Self {
+            storage: ReadonlyPrefixedStorage::multilevel(&[PREFIX_DEPOSITS, depositor.as_slice()], storage),
+        }
+    }
+
+    pub fn len(&self) -> StdResult<u64> {
+        list_len(&self.storage)
+    }
+
+    pub fn is_empty(&self) -> StdResult<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns up to `count` commitments from position `start` on
+    pub fn page(&self, start: u64, count: u64) -> StdResult<Vec<[u8; 32]>> {
+        let end = self.len()?.min(start.saturating_add(count));
+
+        (start..end)
+            .map(|index| load(&self.storage, &list_item_key(index)))
+            .collect()
+    }
+}
+
+
+
+/// Indexes a new pending tx in the global list and its depositor's list
+///
+/// # Arguments
+///
+/// * `store` - a mutable reference to the contract's storage
+/// * `depositor` - the address the deposit came from
+/// * `commitment` - the key the pending tx is stored under
+pub fn add_pending<S: Storage>(
+    store: &mut S,
+    depositor: &CanonicalAddr,
+    commitment: &[u8; 32],
+) -> StdResult<()> {
+    CommitmentList::pending(store).push(commitment)?;
+    CommitmentList::deposits(store, depositor).push(commitment)
+}
+
+
+
+/// Drops a finalized or exited tx from the global list and its depositor's list
+///
+/// # Arguments
+///
+/// * `store` - a mutable reference to the contract's storage
+/// * `depositor` - the address the deposit came from
+/// * `commitment` - the key the pending tx was stored under
+pub fn remove_pending<S: Storage>(
+    store: &mut S,
+    depositor: &CanonicalAddr,
+    commitment: &[u8; 32],
+) -> StdResult<()> {
+    CommitmentList::pending(store).remove(commitment)?;
+    CommitmentList::deposits(store, depositor).remove(commitment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::MockStorage;
+
+    #[test]
+    fn test_commitment_list_swap_remove() {
+        let mut storage = MockStorage::new();
+        let depositor = CanonicalAddr(Binary::from(b"depositor".to_vec()));
+
+        for i in 0..4u8 {
+            add_pending(&mut storage, &depositor, &[i; 32]).unwrap();
+        }
+
+        remove_pending(&mut storage, &depositor, &[1; 32]).unwrap();
+        // Removing twice leaves the list alone
+        remove_pending(&mut storage, &depositor, &[1; 32]).unwrap();
+
+        let pending = ReadonlyCommitmentList::pending(&storage);
+        assert_eq!(pending.len().unwrap(), 3);
+        assert_eq!(pending.page(0, 10).unwrap(), vec![[0; 32], [3; 32], [2; 32]]);
+        assert_eq!(pending.page(2, 10).unwrap(), vec![[2; 32]]);
+        assert!(pending.page(5, 10).unwrap().is_empty());
+
+        // The moved commitment can still be found for removal
+        remove_pending(&mut storage, &depositor, &[3; 32]).unwrap();
+        remove_pending(&mut storage, &depositor, &[0; 32]).unwrap();
+        remove_pending(&mut storage, &depositor, &[2; 32]).unwrap();
+
+        assert!(ReadonlyCommitmentList::pending(&storage).is_empty().unwrap());
+        assert!(ReadonlyCommitmentList::deposits(&storage, &depositor).is_empty().unwrap());
+    }
+}
@@ -0,0 +1,486 @@
+//! In-process simulation of the contract alongside mock snip20s and the bank module.
+//! Messages returned by the contract are executed against tracked balances, so tests can
+//! follow funds through whole deposit, finalize and exit flows.
+//!
+//! Unlike the chain, contract storage is not rolled back when a returned message fails.
+//! Flows that expect such a failure should not keep using the harness afterwards.
+
+use std::collections::BTreeMap;
+
+use cosmwasm_std::testing::{mock_dependencies, mock_env, MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{
+    from_binary, from_slice, to_binary, BankMsg, Binary, Coin, CosmosMsg, Env, Extern, HandleResponse, HumanAddr,
+    StdError, StdResult, Uint128, WasmMsg,
+};
+use serde::Deserialize;
+
+use crate::contract::{handle, init, query};
+use crate::msg::{FeesResponse, HandleMsg, HandleReceiveMsg, InitMsg, PoolSizeResponse, QueryMsg};
+use crate::state::ReadonlyCommitmentList;
+
+/// The handle messages the contract sends to snip20s
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MockSnip20Msg {
+    Transfer {
+        recipient: HumanAddr,
+        amount: Uint128,
+    },
+    Send {
+        recipient: HumanAddr,
+        amount: Uint128,
+        msg: Option<Binary>,
+    },
+    Redeem {
+        amount: Uint128,
+        denom: Option<String>,
+    },
+    RegisterReceive {},
+}
+
+/// A snip20 Send that reached an address other than the pool
+pub struct SentMsg {
+    pub token: HumanAddr,
+    pub recipient: HumanAddr,
+    pub amount: Uint128,
+    pub msg: Option<Binary>,
+}
+
+pub struct Harness {
+    pub deps: Extern<MockStorage, MockApi, MockQuerier>,
+    pub contract: HumanAddr,
+    pub height: u64,
+    pub time: u64,
+    /// Mock snip20s by address, with the denom they redeem into
+    pub tokens: BTreeMap<HumanAddr, String>,
+    /// Snip20 balances by token, then holder
+    pub snip20_balances: BTreeMap<HumanAddr, BTreeMap<HumanAddr, u128>>,
+    /// Native balances by holder, then denom
+    pub bank_balances: BTreeMap<HumanAddr, BTreeMap<String, u128>>,
+    /// Tokens the pool registered its receive hook with
+    pub registered: Vec<HumanAddr>,
+    pub sent: Vec<SentMsg>,
+}
+
+impl Harness {
+    /// Initializes the pool with a mock snip20 for every (address, denom) given
+    pub fn new(tokens: &[(&str, &str)], init_msg: InitMsg) -> Self {
+        let env = mock_env("admin", &[]);
+
+        let mut harness = Harness {
+            deps: mock_dependencies(20, &[]),
+            contract: env.contract.address.clone(),
+            height: env.block.height,
+            time: env.block.time,
+            tokens: tokens
+                .iter()
+                .map(|(address, denom)| (HumanAddr::from(*address), denom.to_string()))
+                .collect(),
+            snip20_balances: BTreeMap::new(),
+            bank_balances: BTreeMap::new(),
+            registered: vec![],
+            sent: vec![],
+        };
+
+        let response = init(&mut harness.deps, env, init_msg).unwrap();
+        harness.execute(response.messages).unwrap();
+
+        harness
+    }
+
+    /// Moves the chain forward
+    pub fn advance(&mut self, blocks: u64, seconds: u64) {
+        self.height += blocks;
+        self.time += seconds;
+    }
+
+    pub fn mint(&mut self, token: &str, holder: &str, amount: u128) {
+        *self.snip20_balances
+            .entry(HumanAddr::from(token))
+            .or_default()
+            .entry(HumanAddr::from(holder))
+            .or_default() += amount;
+    }
+
+    pub fn mint_native(&mut self, holder: &str, denom: &str, amount: u128) {
+        *self.bank_balances
+            .entry(HumanAddr::from(holder))
+            .or_default()
+            .entry(denom.to_string())
+            .or_default() += amount;
+    }
+
+    pub fn snip20_balance(&self, token: &str, holder: &str) -> u128 {
+        self.snip20_balances
+            .get(&HumanAddr::from(token))
+            .and_then(|balances| balances.get(&HumanAddr::from(holder)))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn bank_balance(&self, holder: &str, denom: &str) -> u128 {
+        self.bank_balances
+            .get(&HumanAddr::from(holder))
+            .and_then(|balances| balances.get(denom))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sends a snip20 to the pool the way the token would, through its Receive hook
+    pub fn deposit(&mut self, token: &str, from: &str, amount: u128, msg: &HandleReceiveMsg) -> StdResult<HandleResponse> {
+        let token = HumanAddr::from(token);
+        let from = HumanAddr::from(from);
+        let contract = self.contract.clone();
+        self.move_snip20(&token, &from, &contract, amount)?;
+
+        let receive = HandleMsg::Receive {
+            sender: from.clone(),
+            from,
+            amount: Uint128(amount),
+            msg: Some(to_binary(msg)?),
+            padding: None,
+        };
+        self.handle_as(token.as_str(), receive, &[])
+    }
+
+    /// Runs a handle message, moving any sent funds to the pool first and executing
+    /// the messages it returns
+    pub fn handle_as(&mut self, sender: &str, msg: HandleMsg, funds: &[Coin]) -> StdResult<HandleResponse> {
+        let sender_addr = HumanAddr::from(sender);
+        let contract = self.contract.clone();
+        for coin in funds {
+            self.move_native(&sender_addr, &contract, &coin.denom, coin.amount.u128())?;
+        }
+
+        let env = self.env(sender, funds);
+        let response = handle(&mut self.deps, env, msg)?;
+        self.execute(response.messages.clone())?;
+
+        Ok(response)
+    }
+
+    pub fn query<T: serde::de::DeserializeOwned>(&self, msg: QueryMsg) -> StdResult<T> {
+        from_binary(&query(&self.deps, msg)?)
+    }
+
+    /// Checks that the pool's own accounting matches what it actually holds. Every
+    /// token must hold exactly its pending gas plus its unwithdrawn fees
+    pub fn check_invariants(&self) {
+        let pool: PoolSizeResponse = self.query(QueryMsg::GetPoolSize {}).unwrap();
+        let fees: FeesResponse = self.query(QueryMsg::GetFees {}).unwrap();
+        let pending = ReadonlyCommitmentList::pending(&self.deps.storage).len().unwrap();
+
+        assert_eq!(pool.pool_size, pending);
+        assert_eq!(pool.pool_size, pool.tokens.iter().map(|t| t.pool_size).sum::<u64>());
+        assert_eq!(pool.tvl.u128(), pool.tokens.iter().map(|t| t.amount.u128()).sum::<u128>());
+
+        let mut held: BTreeMap<HumanAddr, u128> = BTreeMap::new();
+        for token in pool.tokens.iter() {
+            *held.entry(token.token.clone()).or_default() += token.amount.u128();
+        }
+        for fee in fees.fees.iter() {
+            *held.entry(fee.token.clone()).or_default() += fee.admin_accrued.u128() + fee.operator_accrued.u128();
+        }
+
+        for (token, expected) in held.iter() {
+            let actual = if self.tokens.contains_key(token) {
+                self.snip20_balance(token.as_str(), self.contract.as_str())
+            } else {
+                // Native coins are registered under their denom
+                self.bank_balance(self.contract.as_str(), token.as_str())
+            };
+            assert_eq!(actual, *expected, "pool holds the wrong amount of {}", token);
+        }
+
+        // Redeemed coins are always sent on in the same transaction
+        for denom in self.tokens.values() {
+            if !held.contains_key(&HumanAddr::from(denom.as_str())) {
+                assert_eq!(self.bank_balance(self.contract.as_str(), denom), 0);
+            }
+        }
+    }
+
+    fn env(&self, sender: &str, funds: &[Coin]) -> Env {
+        let mut env = mock_env(sender, funds);
+        env.block.height = self.height;
+        env.block.time = self.time;
+        env
+    }
+
+    fn execute(&mut self, messages: Vec<CosmosMsg>) -> StdResult<()> {
+        let contract = self.contract.clone();
+
+        for message in messages {
+            match message {
+                CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                    let denom = match self.tokens.get(&contract_addr) {
+                        Some(denom) => denom.clone(),
+                        None => return Err(StdError::generic_err(format!("No mock contract at {}", contract_addr))),
+                    };
+
+                    match from_slice(msg.as_slice())? {
+                        MockSnip20Msg::Transfer { recipient, amount } => {
+                            self.move_snip20(&contract_addr, &contract, &recipient, amount.u128())?;
+                        }
+                        MockSnip20Msg::Send { recipient, amount, msg } => {
+                            self.move_snip20(&contract_addr, &contract, &recipient, amount.u128())?;
+                            self.sent.push(SentMsg { token: contract_addr, recipient, amount, msg });
+                        }
+                        MockSnip20Msg::Redeem { amount, denom: redeem_denom } => {
+                            if redeem_denom.unwrap_or_else(|| denom.clone()) != denom {
+                                return Err(StdError::generic_err("Redeem in the wrong denom"));
+                            }
+                            let burn = HumanAddr::default();
+                            self.move_snip20(&contract_addr, &contract, &burn, amount.u128())?;
+                            self.mint_native(contract.as_str(), &denom, amount.u128());
+                        }
+                        MockSnip20Msg::RegisterReceive { .. } => self.registered.push(contract_addr),
+                    }
+                }
+                CosmosMsg::Bank(BankMsg::Send { from_address, to_address, amount }) => {
+                    for coin in amount {
+                        self.move_native(&from_address, &to_address, &coin.denom, coin.amount.u128())?;
+                    }
+                }
+                other => return Err(StdError::generic_err(format!("Unsupported message {:?}", other))),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn move_snip20(&mut self, token: &HumanAddr, from: &HumanAddr, to: &HumanAddr, amount: u128) -> StdResult<()> {
+        let balances = self.snip20_balances.entry(token.clone()).or_default();
+
+        let from_balance = balances.entry(from.clone()).or_default();
+        if *from_balance < amount {
+            return Err(StdError::generic_err(format!("{} has insufficient {}", from, token)));
+        }
+        *from_balance -= amount;
+
+        *balances.entry(to.clone()).or_default() += amount;
+        Ok(())
+    }
+
+    fn move_native(&mut self, from: &HumanAddr, to: &HumanAddr, denom: &str, amount: u128) -> StdResult<()> {
+        let from_balance = self.bank_balances.entry(from.clone()).or_default().entry(denom.to_string()).or_default();
+        if *from_balance < amount {
+            return Err(StdError::generic_err(format!("{} has insufficient {}", from, denom)));
+        }
+        *from_balance -= amount;
+
+        *self.bank_balances.entry(to.clone()).or_default().entry(denom.to_string()).or_default() += amount;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::coins;
+
+    use crate::msg::{Destination, TokenInfo};
+    use crate::state::{FeeSchedule, Payout};
+
+    fn fee_schedule() -> FeeSchedule {
+        FeeSchedule {
+            flat: Uint128(100_000),
+            bps: 0,
+            min: Uint128::zero(),
+            max: None,
+            tiers: vec![],
+            operator_share_bps: 5_000,
+        }
+    }
+
+    fn harness(denominations: Vec<Uint128>) -> Harness {
+        let init_msg = InitMsg {
+            admin: HumanAddr::from("admin"),
+            operators: vec![HumanAddr::from("operator")],
+            tokens: vec![TokenInfo {
+                address: HumanAddr::from("sscrt"),
+                code_hash: "sscrt_hash".to_string(),
+                denom: "uscrt".to_string(),
+                native: false,
+                fee: fee_schedule(),
+                denominations,
+            }],
+            min_delay: 60,
+            min_pool_size: 1,
+            expiry_blocks: 100,
+            entropy: "init entropy".to_string(),
+        };
+
+        let mut harness = Harness::new(&[("sscrt", "uscrt")], init_msg);
+        harness.mint("sscrt", "alice", 10_000_000);
+        harness.mint("sscrt", "bob", 10_000_000);
+        harness
+    }
+
+    fn seed(destination: &str, payout: Payout) -> HandleReceiveMsg {
+        HandleReceiveMsg::ReceiveSeed {
+            destinations: vec![Destination { address: HumanAddr::from(destination), weight: Uint128(1), commitment: None }],
+            destination: None,
+            entropy: None,
+            payout,
+        }
+    }
+
+    fn tx_code(response: &HandleResponse) -> String {
+        response.log.iter().find(|l| l.key == "tx_code").unwrap().value.trim().to_string()
+    }
+
+    #[test]
+    fn test_deposit_and_finalize() {
+        let mut h = harness(vec![]);
+        assert_eq!(h.registered, vec![HumanAddr::from("sscrt")]);
+
+        let first = h.deposit("sscrt", "alice", 1_100_000, &seed("wallet1", Payout::Native)).unwrap();
+        h.check_invariants();
+
+        // Neither the pool size nor the delay has been reached
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_code(&first)], padding: None };
+        assert!(h.handle_as("operator", finalize.clone(), &[]).is_err());
+
+        let hook = Some(Binary::from(b"hook".to_vec()));
+        let second = h.deposit("sscrt", "bob", 2_100_000, &seed("wallet2", Payout::Send { msg: hook.clone() })).unwrap();
+        assert!(h.handle_as("operator", finalize, &[]).is_err());
+
+        // The batch may not empty the pool it hides in
+        h.advance(10, 60);
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_code(&first), tx_code(&second)], padding: None };
+        assert!(h.handle_as("operator", finalize.clone(), &[]).is_err());
+
+        h.mint("sscrt", "carol", 1_100_000);
+        h.deposit("sscrt", "carol", 1_100_000, &seed("wallet3", Payout::Native)).unwrap();
+        h.handle_as("operator", finalize, &[]).unwrap();
+        h.check_invariants();
+
+        assert_eq!(h.bank_balance("wallet1", "uscrt"), 1_000_000);
+        assert_eq!(h.snip20_balance("sscrt", "wallet2"), 2_000_000);
+        assert_eq!(h.sent.len(), 1);
+        assert_eq!(h.sent[0].token, HumanAddr::from("sscrt"));
+        assert_eq!(h.sent[0].recipient, HumanAddr::from("wallet2"));
+        assert_eq!(h.sent[0].amount, Uint128(2_000_000));
+        assert_eq!(h.sent[0].msg, hook);
+        assert_eq!(h.snip20_balance("sscrt", "alice"), 8_900_000);
+        assert_eq!(h.snip20_balance("sscrt", h.contract.as_str()), 1_300_000);
+    }
+
+    #[test]
+    fn test_exit_and_reclaim_return_funds() {
+        let mut h = harness(vec![]);
+
+        let response = h.deposit("sscrt", "alice", 1_100_000, &seed("wallet", Payout::Native)).unwrap();
+        h.deposit("sscrt", "bob", 1_100_000, &seed("wallet", Payout::Native)).unwrap();
+
+        // Exits skip the release conditions and pay whoever holds the key
+        h.handle_as("alice", HandleMsg::ExitPool { tx_key: tx_code(&response), padding: None }, &[]).unwrap();
+        h.check_invariants();
+        assert_eq!(h.snip20_balance("sscrt", "alice"), 9_900_000);
+
+        let reclaim = HandleMsg::ReclaimExpired { depositor: HumanAddr::from("bob"), page: None, page_size: 10, padding: None };
+        assert!(h.handle_as("carol", reclaim.clone(), &[]).is_err());
+
+        h.advance(100, 600);
+        h.handle_as("carol", reclaim, &[]).unwrap();
+        h.check_invariants();
+        assert_eq!(h.snip20_balance("sscrt", "bob"), 9_900_000);
+    }
+
+    #[test]
+    fn test_fees_and_denomination_refunds() {
+        let mut h = harness(vec![Uint128(1_000_000), Uint128(100_000)]);
+
+        let split = HandleReceiveMsg::ReceiveSeed {
+            destinations: vec![
+                Destination { address: HumanAddr::from("wallet1"), weight: Uint128(1), commitment: None },
+                Destination { address: HumanAddr::from("wallet2"), weight: Uint128(1), commitment: None },
+            ],
+            destination: None,
+            entropy: None,
+            payout: Payout::Native,
+        };
+        // 2_450_000 after fees splits into two 1_000_000 outputs, refunding 450_000
+        h.deposit("sscrt", "alice", 2_550_000, &split).unwrap();
+        h.check_invariants();
+        assert_eq!(h.snip20_balance("sscrt", "alice"), 7_900_000);
+
+        h.handle_as("admin", HandleMsg::WithdrawFees { padding: None }, &[]).unwrap();
+        h.handle_as("operator", HandleMsg::WithdrawFees { padding: None }, &[]).unwrap();
+        h.check_invariants();
+        assert_eq!(h.snip20_balance("sscrt", "admin"), 50_000);
+        assert_eq!(h.bank_balance("operator", "uscrt"), 50_000);
+    }
+
+    #[test]
+    fn test_native_deposit_and_finalize() {
+        let mut h = harness(vec![]);
+
+        let native = TokenInfo {
+            address: HumanAddr::from("uscrt"),
+            code_hash: String::new(),
+            denom: "uscrt".to_string(),
+            native: true,
+            fee: fee_schedule(),
+            denominations: vec![],
+        };
+        h.handle_as("admin", HandleMsg::AddToken { token: native, padding: None }, &[]).unwrap();
+        h.mint_native("alice", "uscrt", 5_000_000);
+
+        let deposit = |destination: &str| HandleMsg::DepositNative {
+            destinations: vec![Destination { address: HumanAddr::from(destination), weight: Uint128(1), commitment: None }],
+            entropy: None,
+            payout: Payout::Native,
+            padding: None,
+        };
+        let first = h.handle_as("alice", deposit("wallet1"), &coins(1_100_000, "uscrt")).unwrap();
+        let second = h.handle_as("alice", deposit("wallet2"), &coins(1_100_000, "uscrt")).unwrap();
+        h.check_invariants();
+
+        h.advance(10, 60);
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_code(&first), tx_code(&second)], padding: None };
+        assert!(h.handle_as("operator", finalize, &[]).is_err());
+
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_code(&first)], padding: None };
+        h.handle_as("operator", finalize, &[]).unwrap();
+        h.check_invariants();
+
+        assert_eq!(h.bank_balance("wallet1", "uscrt"), 1_000_000);
+        assert_eq!(h.bank_balance("alice", "uscrt"), 2_800_000);
+        assert_eq!(h.bank_balance(h.contract.as_str(), "uscrt"), 1_200_000);
+    }
+
+    #[test]
+    fn test_refunded_remainder_pays_no_fee() {
+        let mut h = harness(vec![Uint128(1_000_000), Uint128(100_000)]);
+
+        let fee = FeeSchedule { flat: Uint128::zero(), bps: 100, ..fee_schedule() };
+        h.handle_as("admin", HandleMsg::ChangeFee { token: HumanAddr::from("sscrt"), fee, padding: None }, &[]).unwrap();
+
+        // 1% of the pooled 1_000_000, not of the whole 1_500_000 deposit
+        h.deposit("sscrt", "alice", 1_500_000, &seed("wallet", Payout::Native)).unwrap();
+        h.check_invariants();
+        assert_eq!(h.snip20_balance("sscrt", "alice"), 8_990_000);
+        assert_eq!(h.snip20_balance("sscrt", h.contract.as_str()), 1_010_000);
+    }
+
+    #[test]
+    fn test_pool_threshold_is_per_bucket() {
+        let mut h = harness(vec![Uint128(1_000_000), Uint128(100_000)]);
+
+        let large = h.deposit("sscrt", "alice", 1_100_000, &seed("wallet1", Payout::Native)).unwrap();
+        h.deposit("sscrt", "bob", 200_000, &seed("wallet2", Payout::Native)).unwrap();
+        h.advance(10, 60);
+
+        // The token still holds a tx, but none of the same denomination
+        let finalize = HandleMsg::FinalizeSeed { tx_keys: vec![tx_code(&large)], padding: None };
+        assert!(h.handle_as("operator", finalize.clone(), &[]).is_err());
+
+        h.deposit("sscrt", "bob", 1_100_000, &seed("wallet3", Payout::Native)).unwrap();
+        h.handle_as("operator", finalize, &[]).unwrap();
+        h.check_invariants();
+        assert_eq!(h.bank_balance("wallet1", "uscrt"), 1_000_000);
+    }
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::rand::sha_256;
+
+pub const VIEWING_KEY_SIZE: usize = 32;
+pub const VIEWING_KEY_PREFIX: &str = "api_key_";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ViewingKey(pub String);
+
+impl ViewingKey {
+    /// Builds a new key from 32 random bytes, e.g. the output of `contract::new_entropy`
+    pub fn new(rand_bytes: &[u8]) -> Self {
+        let key = sha_256(rand_bytes);
+
+        Self(VIEWING_KEY_PREFIX.to_string() + &base64::encode(key))
+    }
+
+    pub fn check_viewing_key(&self, hashed_pw: &[u8]) -> bool {
+        let mine_hashed = create_hashed_password(&self.0);
+
+        ct_slice_compare(&mine_hashed, hashed_pw)
+    }
+
+    pub fn to_hashed(&self) -> [u8; VIEWING_KEY_SIZE] {
+        create_hashed_password(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl fmt::Display for ViewingKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Compares two slices without exiting early on the first difference
+fn ct_slice_compare(s1: &[u8], s2: &[u8]) -> bool {
+    if s1.len() != s2.len() {
+        return false;
+    }
+
+    s1.iter().zip(s2.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub fn create_hashed_password(s1: &str) -> [u8; VIEWING_KEY_SIZE] {
+    sha_256(s1.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_viewing_key() {
+        let key = ViewingKey::new(b"some random bytes");
+        let hashed = key.to_hashed();
+
+        assert!(key.check_viewing_key(&hashed));
+        assert!(!ViewingKey("wrong key".to_string()).check_viewing_key(&hashed));
+        assert!(!key.check_viewing_key(&hashed[..31]));
+    }
+}